@@ -0,0 +1,519 @@
+//! Versioned JSON API for automation, /metrics stays the Prometheus interface.
+//!
+//! Every response is an envelope, either `{"status": "success", "data": ...}` or
+//! `{"status": "error", "error": {"code": ..., "message": ...}}`. Codes are stable, messages are
+//! meant for humans and may change.
+
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use axum::{
+    extract::{rejection::JsonRejection, rejection::QueryRejection, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::time::timeout;
+
+use crate::{
+    config::Config,
+    error::ReadError,
+    modbus::{default_unit_id, deserialize_host, read_samples, Device, ModbusState, Series},
+    registers::metric_table,
+    targets::{target_infos, TargetInfo},
+    tenants::Denied,
+};
+
+pub fn router() -> Router<ModbusState> {
+    Router::new()
+        .route("/query", get(query).fallback(method_not_allowed))
+        .route("/targets", get(targets).fallback(method_not_allowed))
+        .route("/history", get(history).fallback(method_not_allowed))
+        .route("/reload", post(reload).fallback(method_not_allowed))
+        .route("/control", post(control).fallback(method_not_allowed))
+        .fallback(not_found)
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum Envelope<T> {
+    Success { data: T },
+    Error { error: ErrorBody },
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+}
+
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError { status, code, message: message.into() }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = Envelope::<()>::Error {
+            error: ErrorBody { code: self.code, message: self.message },
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+impl From<ReadError> for ApiError {
+    fn from(error: ReadError) -> Self {
+        let (status, code) = match &error {
+            ReadError::Connect { .. } => (StatusCode::BAD_GATEWAY, "target_unreachable"),
+            ReadError::Timeout { .. } => (StatusCode::GATEWAY_TIMEOUT, "target_timeout"),
+            ReadError::Exception { exception } if exception.is_unsupported() => {
+                (StatusCode::BAD_GATEWAY, "register_unsupported")
+            }
+            ReadError::Exception { .. } => (StatusCode::SERVICE_UNAVAILABLE, "target_busy"),
+            ReadError::Decode { .. } => (StatusCode::BAD_GATEWAY, "invalid_response"),
+            ReadError::Reset { .. } | ReadError::Io { .. } => (StatusCode::BAD_GATEWAY, "connection_lost"),
+            ReadError::JsonRpc { .. } => (StatusCode::BAD_GATEWAY, "jsonrpc_failed"),
+        };
+        ApiError::new(status, code, error.to_string())
+    }
+}
+
+impl From<Denied> for ApiError {
+    fn from(denied: Denied) -> Self {
+        let code = match denied {
+            Denied::Unauthorized => "unauthorized",
+            Denied::Forbidden => "forbidden",
+        };
+        ApiError::new(denied.status(), code, denied.message())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::bad_request(rejection.body_text())
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        ApiError::bad_request(rejection.body_text())
+    }
+}
+
+type ApiResult<T> = Result<Json<Envelope<T>>, ApiError>;
+
+fn success<T>(data: T) -> ApiResult<T> {
+    Ok(Json(Envelope::Success { data }))
+}
+
+/// Rejects requests that don't accept JSON, a missing Accept header accepts anything.
+fn negotiate(headers: &HeaderMap) -> Result<(), ApiError> {
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|a| a.to_str().ok()) else {
+        return Ok(());
+    };
+
+    let acceptable = accept.split(',').any(|range| {
+        let media_type = range.split(';').next().unwrap_or_default().trim();
+        matches!(media_type, "application/json" | "application/*" | "*/*")
+    });
+
+    if acceptable {
+        Ok(())
+    } else {
+        Err(ApiError::new(
+            StatusCode::NOT_ACCEPTABLE,
+            "not_acceptable",
+            "the API only responds with application/json",
+        ))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QueryParams {
+    #[serde(deserialize_with = "deserialize_host")]
+    host: SocketAddr,
+    #[serde(default = "default_unit_id")]
+    unit_id: u8,
+    /// Defaults to the `fems_id` of the configured target, see [`ModbusState::default_fems_id`]
+    fems_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SeriesData {
+    name: String,
+    labels: BTreeMap<String, String>,
+    value: f64,
+}
+
+impl From<Series> for SeriesData {
+    fn from(series: Series) -> Self {
+        SeriesData {
+            name: series.name,
+            labels: series.labels.into_iter().collect(),
+            value: series.value.as_f64(),
+        }
+    }
+}
+
+/// Reads a device like /metrics does, returning the relabeled series.
+async fn query(
+    headers: HeaderMap,
+    params: Result<Query<QueryParams>, QueryRejection>,
+    State(state): State<ModbusState>,
+) -> ApiResult<Vec<SeriesData>> {
+    negotiate(&headers)?;
+    let scope = state.tenants.authorize(&headers)?;
+    let Query(QueryParams { host, unit_id, fems_id }) = params?;
+
+    let device = Device { host, unit_id };
+    let fems_id = fems_id.unwrap_or_else(|| state.default_fems_id(device));
+    state.tenants.check(&scope, device, &fems_id)?;
+    let mut samples = read_samples(&state, device).await?;
+    samples.extend(state.scrape_samples(device));
+
+    success(state.series(&samples, &fems_id).into_iter().map(SeriesData::from).collect())
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HistoryParams {
+    #[serde(deserialize_with = "deserialize_host")]
+    host: SocketAddr,
+    #[serde(default = "default_unit_id")]
+    unit_id: u8,
+    /// Defaults to the `fems_id` of the configured target, see [`ModbusState::default_fems_id`]
+    fems_id: Option<String>,
+    /// Comma separated channel addresses, e.g. `_sum/EssSoc,_sum/GridActivePower`
+    channels: String,
+    /// First day, `YYYY-MM-DD`
+    from: String,
+    /// Last day, `YYYY-MM-DD`
+    to: String,
+    /// Time zone the days are in, as known to the edge
+    #[serde(default = "default_timezone")]
+    timezone: String,
+    /// Minutes between two values
+    #[serde(default = "default_resolution")]
+    resolution: u32,
+}
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+fn default_resolution() -> u32 {
+    15
+}
+
+/// Whether `date` looks like `YYYY-MM-DD`, the edge rejects impossible dates itself.
+fn is_date(date: &str) -> bool {
+    let bytes = date.as_bytes();
+    bytes.len() == 10
+        && bytes.iter().enumerate().all(|(i, b)| match i {
+            4 | 7 => *b == b'-',
+            _ => b.is_ascii_digit(),
+        })
+}
+
+/// Passes `queryHistoricTimeseriesData` on to a target read via JSON-RPC.
+///
+/// The result is returned as the edge sent it, with `timestamps` and the values of each
+/// channel in `data`. Like reads, the query is subject to `--modbus-timeout`.
+async fn history(
+    headers: HeaderMap,
+    params: Result<Query<HistoryParams>, QueryRejection>,
+    State(state): State<ModbusState>,
+) -> ApiResult<Value> {
+    negotiate(&headers)?;
+    let scope = state.tenants.authorize(&headers)?;
+    let Query(params) = params?;
+
+    let device = Device { host: params.host, unit_id: params.unit_id };
+    let fems_id = params.fems_id.clone().unwrap_or_else(|| state.default_fems_id(device));
+    state.tenants.check(&scope, device, &fems_id)?;
+
+    let channels: Vec<&str> = params.channels.split(',').map(str::trim).filter(|c| !c.is_empty()).collect();
+    if let Some(channel) = channels.iter().find(|c| !c.contains('/')) {
+        return Err(ApiError::bad_request(format!("invalid channel {channel:?}, expected <component>/<channel>")));
+    }
+    if channels.is_empty() {
+        return Err(ApiError::bad_request("no channels given"));
+    }
+    if let Some(date) = [&params.from, &params.to].into_iter().find(|d| !is_date(d)) {
+        return Err(ApiError::bad_request(format!("invalid date {date:?}, expected YYYY-MM-DD")));
+    }
+    if params.resolution == 0 {
+        return Err(ApiError::bad_request("resolution must be at least one minute"));
+    }
+
+    let backend = state.backend(device);
+    let Some(client) = backend.jsonrpc() else {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "history_unavailable",
+            format!("{host} unit {unit_id} is not read via JSON-RPC", host = device.host, unit_id = device.unit_id),
+        ));
+    };
+
+    let request = json!({
+        "timezone": params.timezone,
+        "fromDate": params.from,
+        "toDate": params.to,
+        "channels": channels,
+        "resolution": {"value": params.resolution, "unit": "Minutes"},
+    });
+    let result = timeout(state.modbus_timeout, client.request("queryHistoricTimeseriesData", request))
+        .await
+        .unwrap_or_else(|_| Err(ReadError::timeout(state.modbus_timeout)))?;
+
+    success(result)
+}
+
+async fn targets(headers: HeaderMap, State(state): State<ModbusState>) -> ApiResult<Vec<TargetInfo>> {
+    negotiate(&headers)?;
+    let scope = state.tenants.authorize(&headers)?;
+    success(target_infos(&state, &scope))
+}
+
+#[derive(Serialize)]
+struct Reloaded {
+    /// Config sections and target fields that took effect
+    applied: &'static [&'static str],
+    /// Config sections and target fields that are only read on startup
+    restart_required: &'static [&'static str],
+}
+
+/// Reloads the config file, rejecting it if `check-config` would.
+async fn reload(headers: HeaderMap, State(state): State<ModbusState>) -> ApiResult<Reloaded> {
+    negotiate(&headers)?;
+    state.tenants.check_admin(&state.tenants.authorize(&headers)?)?;
+
+    let Some(path) = &state.config_path else {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "no_config_file",
+            "the exporter was started without --config",
+        ));
+    };
+
+    let invalid = |message| ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "invalid_config", message);
+    let config = Config::load(path).map_err(|e| invalid(e.to_string()))?;
+    let problems = config.check();
+    if !problems.is_empty() {
+        return Err(invalid(problems.join("; ")));
+    }
+
+    state.reload(metric_table(&config), config.relabel);
+
+    // Targets only take part in the metric table with their optional groups and custom registers
+    success(Reloaded {
+        applied: &["labels", "faults", "batteries", "io", "relabel", "targets.optional_groups", "targets.registers"],
+        restart_required: &[
+            "targets.host",
+            "targets.unit_id",
+            "targets.fems_id",
+            "targets.backend",
+            "targets.auth",
+            "targets.jsonrpc_url",
+            "targets.predictions",
+            "targets.tenant",
+            "targets.sign_convention",
+            "otlp",
+            "kilowatthours",
+            "number_format",
+            "missing_fems_id",
+            "downsampling",
+            "tenants",
+        ],
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case", deny_unknown_fields)]
+enum ControlRequest {
+    /// Close the connection, the next read reconnects and locates the components again
+    Reconnect {
+        #[serde(deserialize_with = "deserialize_host")]
+        host: SocketAddr,
+    },
+    /// Forget which register groups and read lengths a device supports, so they are probed again
+    Reprobe {
+        #[serde(deserialize_with = "deserialize_host")]
+        host: SocketAddr,
+        #[serde(default = "default_unit_id")]
+        unit_id: u8,
+    },
+    /// Drop all cached samples, including values restored from the state file
+    ClearCache,
+}
+
+#[derive(Serialize)]
+struct ControlDone {
+    action: &'static str,
+}
+
+async fn control(
+    headers: HeaderMap,
+    State(state): State<ModbusState>,
+    request: Result<Json<ControlRequest>, JsonRejection>,
+) -> ApiResult<ControlDone> {
+    negotiate(&headers)?;
+    state.tenants.check_admin(&state.tenants.authorize(&headers)?)?;
+    let Json(request) = request?;
+
+    let action = match request {
+        ControlRequest::Reconnect { host } => {
+            let connection = state.connections.lock().unwrap().get(&host).cloned();
+            let Some(connection) = connection else {
+                return Err(ApiError::new(
+                    StatusCode::NOT_FOUND,
+                    "unknown_target",
+                    format!("no connection to {host}"),
+                ));
+            };
+            *connection.lock().await = None;
+            "reconnect"
+        }
+        ControlRequest::Reprobe { host, unit_id } => {
+            let mut targets = state.targets.lock().unwrap();
+            let Some(status) = targets.get_mut(&Device { host, unit_id }) else {
+                return Err(ApiError::new(
+                    StatusCode::NOT_FOUND,
+                    "unknown_target",
+                    format!("{host} unit {unit_id} has not been scraped"),
+                ));
+            };
+            status.register_groups.clear();
+            status.read_window = None;
+            "reprobe"
+        }
+        ControlRequest::ClearCache => {
+            state.cache.lock().unwrap().clear();
+            "clear_cache"
+        }
+    };
+
+    success(ControlDone { action })
+}
+
+async fn not_found() -> ApiError {
+    ApiError::new(StatusCode::NOT_FOUND, "not_found", "no such API endpoint")
+}
+
+async fn method_not_allowed() -> ApiError {
+    ApiError::new(StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", "method not allowed for this endpoint")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, TcpListener},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use axum::http::Uri;
+
+    use super::*;
+    use crate::{
+        backend::{Backend, Backends, JsonRpc},
+        jsonrpc::JsonRpcClient,
+    };
+
+    /// Serves the JSON-RPC API, answering history queries with `result`.
+    fn serve_history(result: Value) -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let host = listener.local_addr().unwrap();
+
+        let app = Router::new().route(
+            "/jsonrpc",
+            post(move |Json(request): Json<Value>| {
+                let result = result.clone();
+                async move {
+                    assert_eq!(request["method"], "queryHistoricTimeseriesData");
+                    assert_eq!(request["params"]["fromDate"], "2024-05-01");
+                    assert_eq!(request["params"]["channels"], json!(["_sum/EssSoc", "_sum/GridActivePower"]));
+                    assert_eq!(request["params"]["resolution"], json!({"value": 60, "unit": "Minutes"}));
+                    Json(json!({"jsonrpc": "2.0", "id": request["id"], "result": result}))
+                }
+            }),
+        );
+        listener.set_nonblocking(true).unwrap();
+        let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        host
+    }
+
+    fn state(jsonrpc: Option<SocketAddr>) -> ModbusState {
+        let mut backends = Backends::new();
+        if let Some(host) = jsonrpc {
+            let client = JsonRpcClient::new(Some(&format!("http://{host}/jsonrpc")), host.ip(), None).unwrap();
+            backends.insert(Device { host, unit_id: 1 }, Arc::new(JsonRpc::new(client)) as Arc<dyn Backend>);
+        }
+        ModbusState {
+            backends: Arc::new(backends),
+            modbus_timeout: Duration::from_secs(5),
+            ..Default::default()
+        }
+    }
+
+    async fn query_history(state: ModbusState, query: &str) -> ApiResult<Value> {
+        let uri: Uri = format!("/api/v1/history?{query}").parse().unwrap();
+        history(HeaderMap::new(), Query::try_from_uri(&uri), State(state)).await
+    }
+
+    #[test]
+    fn dates_look_like_iso_dates() {
+        assert!(is_date("2024-05-31"));
+        assert!(!is_date("2024-5-31"));
+        assert!(!is_date("2024/05/31"));
+        assert!(!is_date("2024-05-31T00:00"));
+    }
+
+    #[tokio::test]
+    async fn history_is_passed_on_to_the_edge() {
+        let result = json!({"timestamps": ["2024-05-01T00:00:00Z"], "data": {"_sum/EssSoc": [42]}});
+        let host = serve_history(result.clone());
+        let query = format!(
+            "host={host}&channels=_sum/EssSoc,%20_sum/GridActivePower&from=2024-05-01&to=2024-05-02&resolution=60"
+        );
+
+        match query_history(state(Some(host)), &query).await {
+            Ok(Json(Envelope::Success { data })) => assert_eq!(data, result),
+            Ok(_) => panic!("history query failed"),
+            Err(e) => panic!("history query failed: {}", e.message),
+        }
+    }
+
+    #[tokio::test]
+    async fn history_needs_valid_parameters_and_a_jsonrpc_target() {
+        let error = |query: &str| {
+            let query = format!("host=127.0.0.1:502&{query}");
+            async move { query_history(state(None), &query).await.err().map(|e| (e.status, e.code)) }
+        };
+        let bad_request = Some((StatusCode::BAD_REQUEST, "bad_request"));
+
+        assert_eq!(error("channels=EssSoc&from=2024-05-01&to=2024-05-02").await, bad_request);
+        assert_eq!(error("channels=_sum/EssSoc&from=1.5.2024&to=2024-05-02").await, bad_request);
+        // Read via Modbus, which has no history
+        assert_eq!(
+            error("channels=_sum/EssSoc&from=2024-05-01&to=2024-05-02").await,
+            Some((StatusCode::CONFLICT, "history_unavailable"))
+        );
+    }
+}
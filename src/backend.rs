@@ -0,0 +1,520 @@
+//! Ways of reading a target: Modbus/TCP, Modbus RTU via a serial gateway, JSON-RPC, or not at
+//! all when simulating.
+//!
+//! Every configured target has its backend in [`ModbusState::backends`], devices that are only
+//! scraped are read via Modbus/TCP. Backends read the register groups of the metric table, so
+//! samples look the same no matter how they were read.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::future::{BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as Json};
+use tokio::time::timeout;
+
+use crate::{
+    config::Target,
+    error::ReadError,
+    jsonrpc::JsonRpcClient,
+    modbus::{connect_modbus, read_modbus, Device, GroupSamples, ModbusState, Sample, Value},
+    registers::{F32, F64, U16},
+    simulate::Simulated,
+};
+
+/// Backend of a configured target.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum BackendKind {
+    #[default]
+    #[serde(rename = "modbus_tcp")]
+    ModbusTcp,
+    /// Modbus RTU frames sent over TCP, to a serial gateway at the target's host
+    #[serde(rename = "modbus_rtu")]
+    ModbusRtu,
+    /// The OpenEMS JSON-RPC API at the target's `jsonrpc_url`
+    #[serde(rename = "jsonrpc")]
+    JsonRpc,
+    /// Synthetic values, see [`crate::simulate`]
+    #[serde(rename = "simulated")]
+    Simulated,
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BackendKind::ModbusTcp => "modbus_tcp",
+            BackendKind::ModbusRtu => "modbus_rtu",
+            BackendKind::JsonRpc => "jsonrpc",
+            BackendKind::Simulated => "simulated",
+        })
+    }
+}
+
+/// Outcome of a successful read.
+pub struct Reading {
+    pub groups: Vec<GroupSamples>,
+    /// Registers read, 0 for backends without registers
+    pub registers_read: u32,
+    /// Time the read took, without waiting for the connection
+    pub duration: Duration,
+}
+
+/// Reads the metric table from devices.
+///
+/// Implementations time out and retry their reads themselves, failed reads drop the connection.
+pub trait Backend: Send + Sync {
+    fn kind(&self) -> BackendKind;
+
+    /// Opens the connection to `device` unless there already is one.
+    fn connect<'a>(&'a self, state: &'a ModbusState, device: Device) -> BoxFuture<'a, Result<(), ReadError>>;
+
+    /// Reads the groups in `due` or all supported ones from `device`.
+    fn read_metrics<'a>(
+        &'a self,
+        state: &'a ModbusState,
+        device: Device,
+        due: Option<&'a HashSet<String>>,
+    ) -> BoxFuture<'a, Result<Reading, ReadError>>;
+
+    /// The JSON-RPC client of backends that talk to the OpenEMS API, e.g. to query history.
+    fn jsonrpc(&self) -> Option<&JsonRpcClient> {
+        None
+    }
+}
+
+/// Backends of the configured targets.
+pub type Backends = HashMap<Device, Arc<dyn Backend>>;
+
+/// How Modbus requests are framed on the wire.
+#[derive(Clone, Copy)]
+pub enum Framing {
+    Tcp,
+    Rtu,
+}
+
+pub struct ModbusTcp;
+
+impl Backend for ModbusTcp {
+    fn kind(&self) -> BackendKind {
+        BackendKind::ModbusTcp
+    }
+
+    fn connect<'a>(&'a self, state: &'a ModbusState, device: Device) -> BoxFuture<'a, Result<(), ReadError>> {
+        connect_modbus(state, Framing::Tcp, device).boxed()
+    }
+
+    fn read_metrics<'a>(
+        &'a self,
+        state: &'a ModbusState,
+        device: Device,
+        due: Option<&'a HashSet<String>>,
+    ) -> BoxFuture<'a, Result<Reading, ReadError>> {
+        read_modbus(state, Framing::Tcp, device, due).boxed()
+    }
+}
+
+/// Modbus RTU, for FEMS whose Modbus slave is only reachable via a serial-to-TCP gateway.
+pub struct ModbusRtu;
+
+impl Backend for ModbusRtu {
+    fn kind(&self) -> BackendKind {
+        BackendKind::ModbusRtu
+    }
+
+    fn connect<'a>(&'a self, state: &'a ModbusState, device: Device) -> BoxFuture<'a, Result<(), ReadError>> {
+        connect_modbus(state, Framing::Rtu, device).boxed()
+    }
+
+    fn read_metrics<'a>(
+        &'a self,
+        state: &'a ModbusState,
+        device: Device,
+        due: Option<&'a HashSet<String>>,
+    ) -> BoxFuture<'a, Result<Reading, ReadError>> {
+        read_modbus(state, Framing::Rtu, device, due).boxed()
+    }
+}
+
+/// Registers of `_sum` and the channels with the same values, as addressed in the metric table.
+const SUM_CHANNELS: [(u16, &str); 33] = [
+    (222, "State"),
+    (302, "EssSoc"),
+    (303, "EssActivePower"),
+    (309, "EssReactivePower"),
+    (315, "GridActivePower"),
+    (327, "ProductionActivePower"),
+    (339, "ProductionDcActualPower"),
+    (343, "ConsumptionActivePower"),
+    (351, "EssActiveChargeEnergy"),
+    (355, "EssActiveDischargeEnergy"),
+    (359, "GridBuyActiveEnergy"),
+    (363, "GridSellActiveEnergy"),
+    (367, "ProductionActiveEnergy"),
+    (371, "ProductionAcActiveEnergy"),
+    (375, "ProductionDcActiveEnergy"),
+    (379, "ConsumptionActiveEnergy"),
+    (383, "EssDcChargeEnergy"),
+    (387, "EssDcDischargeEnergy"),
+    (391, "EssActivePowerL1"),
+    (393, "EssActivePowerL2"),
+    (395, "EssActivePowerL3"),
+    (397, "GridActivePowerL1"),
+    (399, "GridActivePowerL2"),
+    (401, "GridActivePowerL3"),
+    (403, "ProductionAcActivePowerL1"),
+    (405, "ProductionAcActivePowerL2"),
+    (407, "ProductionAcActivePowerL3"),
+    (409, "ConsumptionActivePowerL1"),
+    (411, "ConsumptionActivePowerL2"),
+    (413, "ConsumptionActivePowerL3"),
+    (415, "EssDischargePower"),
+    (417, "GridMode"),
+    (418, "EssCapacity"),
+];
+
+/// The OpenEMS JSON-RPC API, for FEMS that don't have the Modbus/TCP API enabled.
+///
+/// Only metrics of `_sum` are available, read with a single `getChannelsValues` request.
+pub struct JsonRpc {
+    client: JsonRpcClient,
+}
+
+impl JsonRpc {
+    pub fn new(client: JsonRpcClient) -> Self {
+        JsonRpc { client }
+    }
+
+    async fn read(&self, state: &ModbusState, device: Device, due: Option<&HashSet<String>>) -> Result<Reading, ReadError> {
+        let table = state.table();
+        let mut reads = Vec::new();
+        for group in table.iter() {
+            if due.is_some_and(|due| !due.contains(&group.name)) || !group.applies_to(device) || group.component != "_sum" {
+                continue;
+            }
+            for metric in &group.metrics {
+                if let Some((_, channel)) = SUM_CHANNELS.iter().find(|(address, _)| *address == metric.address) {
+                    reads.push((&group.name, metric, format!("_sum/{channel}")));
+                }
+            }
+        }
+
+        let started_at = Instant::now();
+        if reads.is_empty() {
+            return Ok(Reading { groups: Vec::new(), registers_read: 0, duration: started_at.elapsed() });
+        }
+
+        let channels: Vec<&str> = reads.iter().map(|(_, _, channel)| channel.as_str()).collect();
+        let mut attempt = 0;
+        let values = loop {
+            let result = timeout(state.modbus_timeout, self.client.request("getChannelsValues", json!({"channels": channels})))
+                .await
+                .unwrap_or_else(|_| Err(ReadError::timeout(state.modbus_timeout)));
+            match result {
+                Ok(values) => break values,
+                Err(e) if state.retry(device, &e, &mut attempt) => {}
+                Err(e) => return Err(e),
+            }
+        };
+        let duration = started_at.elapsed();
+
+        let mut groups: Vec<GroupSamples> = Vec::new();
+        for (group, metric, channel) in &reads {
+            // Channels without a value yet are null
+            let Some(value) = values.get(channel).and_then(Json::as_f64) else {
+                continue;
+            };
+            let value = match metric.modbus_type {
+                U16 => Value::U16(value as u16),
+                F32 => Value::F32(value as f32),
+                F64 => Value::F64(value),
+                _ => continue,
+            };
+            let sample = Sample {
+                name: metric.name.clone(),
+                labels: metric.labels.clone(),
+                value,
+            };
+
+            match groups.last_mut() {
+                Some((name, samples)) if name == *group => samples.push(sample),
+                _ => groups.push((group.to_string(), vec![sample])),
+            }
+        }
+
+        Ok(Reading { groups, registers_read: 0, duration })
+    }
+}
+
+impl Backend for JsonRpc {
+    fn kind(&self) -> BackendKind {
+        BackendKind::JsonRpc
+    }
+
+    /// HTTP needs no connection to be kept open, so there is nothing to prepare.
+    fn connect<'a>(&'a self, _state: &'a ModbusState, _device: Device) -> BoxFuture<'a, Result<(), ReadError>> {
+        async { Ok(()) }.boxed()
+    }
+
+    fn read_metrics<'a>(
+        &'a self,
+        state: &'a ModbusState,
+        device: Device,
+        due: Option<&'a HashSet<String>>,
+    ) -> BoxFuture<'a, Result<Reading, ReadError>> {
+        self.read(state, device, due).boxed()
+    }
+
+    fn jsonrpc(&self) -> Option<&JsonRpcClient> {
+        Some(&self.client)
+    }
+}
+
+/// The backend configured for `target`.
+pub fn new(target: &Target) -> Result<Arc<dyn Backend>, String> {
+    Ok(match target.backend {
+        BackendKind::ModbusTcp => Arc::new(ModbusTcp),
+        BackendKind::ModbusRtu => Arc::new(ModbusRtu),
+        BackendKind::JsonRpc => {
+            let client = JsonRpcClient::new(target.jsonrpc_url.as_deref(), target.host.ip(), target.auth.as_ref())?;
+            Arc::new(JsonRpc::new(client))
+        }
+        BackendKind::Simulated => Arc::new(Simulated),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr, TcpListener as StdTcpListener},
+        sync::{Arc, RwLock},
+        time::Duration,
+    };
+
+    use axum::{routing::post, Json as JsonBody, Router};
+    use serde_json::{json, Value as Json};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::registers::{Group, MetricDef, MetricKind};
+
+    fn metric(name: &str, address: u16) -> MetricDef {
+        MetricDef {
+            name: name.to_string(),
+            labels: Vec::new(),
+            address,
+            modbus_type: U16,
+            kind: MetricKind::Gauge,
+            bits: Vec::new(),
+        }
+    }
+
+    fn state(backends: Backends, retries: u32) -> ModbusState {
+        let table = vec![
+            Group {
+                name: "state".to_string(),
+                component: "_sum".to_string(),
+                metrics: vec![metric("fems_state", 222)],
+                modules: None,
+                devices: None,
+            },
+            Group {
+                name: "ess".to_string(),
+                component: "_sum".to_string(),
+                metrics: vec![metric("fems_ess_soc_percent", 302)],
+                modules: None,
+                devices: None,
+            },
+        ];
+
+        ModbusState {
+            table: Arc::new(RwLock::new(Arc::new(table))),
+            modbus_timeout: Duration::from_secs(5),
+            modbus_retries: retries,
+            backends: Arc::new(backends),
+            ..Default::default()
+        }
+    }
+
+    fn device(host: SocketAddr) -> Device {
+        Device { host, unit_id: 1 }
+    }
+
+    fn values(reading: &Reading) -> Vec<(String, f64)> {
+        reading
+            .groups
+            .iter()
+            .flat_map(|(_, samples)| samples)
+            .map(|s| (s.name.clone(), s.value.as_f64()))
+            .collect()
+    }
+
+    /// Modbus RTU CRC, sent least significant byte first.
+    fn crc(data: &[u8]) -> u16 {
+        data.iter().fold(0xffff, |crc, byte| {
+            (0..8).fold(crc ^ u16::from(*byte), |crc, _| match crc & 1 {
+                1 => (crc >> 1) ^ 0xa001,
+                _ => crc >> 1,
+            })
+        })
+    }
+
+    /// Response to reading input registers, every register holds its address.
+    fn registers(start: u16, count: u16) -> Vec<u8> {
+        let mut pdu = vec![0x04, (count * 2) as u8];
+        for address in start..start + count {
+            pdu.extend_from_slice(&address.to_be_bytes());
+        }
+        pdu
+    }
+
+    /// Serves Modbus/TCP, connections after the first `drop` ones are answered.
+    async fn serve_tcp(drop: usize) -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let host = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            for connection in 0.. {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                if connection < drop {
+                    continue;
+                }
+                tokio::spawn(async move {
+                    let mut request = [0; 12];
+                    while socket.read_exact(&mut request).await.is_ok() {
+                        let start = u16::from_be_bytes([request[8], request[9]]);
+                        let count = u16::from_be_bytes([request[10], request[11]]);
+                        let pdu = registers(start, count);
+
+                        let mut response = request[..4].to_vec();
+                        response.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+                        response.push(request[6]);
+                        response.extend_from_slice(&pdu);
+                        socket.write_all(&response).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        host
+    }
+
+    /// Serves Modbus RTU frames over TCP, like a serial gateway.
+    async fn serve_rtu() -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let host = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 8];
+            while socket.read_exact(&mut request).await.is_ok() {
+                assert_eq!(crc(&request[..6]).to_le_bytes(), request[6..]);
+                let start = u16::from_be_bytes([request[2], request[3]]);
+                let count = u16::from_be_bytes([request[4], request[5]]);
+
+                let mut response = vec![request[0]];
+                response.extend_from_slice(&registers(start, count));
+                response.extend_from_slice(&crc(&response).to_le_bytes());
+                socket.write_all(&response).await.unwrap();
+            }
+        });
+
+        host
+    }
+
+    /// Serves the JSON-RPC API, answering every request with `result`.
+    fn serve_jsonrpc(result: Json) -> SocketAddr {
+        let listener = StdTcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let host = listener.local_addr().unwrap();
+
+        let app = Router::new().route(
+            "/jsonrpc",
+            post(move |JsonBody(request): JsonBody<Json>| {
+                let result = result.clone();
+                async move {
+                    assert_eq!(request["method"], "getChannelsValues");
+                    JsonBody(json!({"jsonrpc": "2.0", "id": request["id"], "result": result}))
+                }
+            }),
+        );
+        listener.set_nonblocking(true).unwrap();
+        let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        host
+    }
+
+    #[tokio::test]
+    async fn unconfigured_devices_use_modbus_tcp() {
+        let configured = device("127.0.0.1:502".parse().unwrap());
+        let mut backends = Backends::new();
+        backends.insert(configured, Arc::new(ModbusRtu) as Arc<dyn Backend>);
+        let state = state(backends, 0);
+
+        assert!(state.backend(configured).kind() == BackendKind::ModbusRtu);
+        assert!(state.backend(device("127.0.0.2:502".parse().unwrap())).kind() == BackendKind::ModbusTcp);
+    }
+
+    #[tokio::test]
+    async fn modbus_tcp_reads_registers() {
+        let device = device(serve_tcp(0).await);
+        let state = state(Backends::new(), 0);
+
+        let reading = ModbusTcp.read_metrics(&state, device, None).await.unwrap();
+        assert_eq!(
+            values(&reading),
+            [("fems_state".to_string(), 222.0), ("fems_ess_soc_percent".to_string(), 302.0)]
+        );
+        assert_eq!(reading.registers_read, 2);
+    }
+
+    #[tokio::test]
+    async fn modbus_tcp_retries_lost_connections() {
+        let state = state(Backends::new(), 0);
+        let error = ModbusTcp.read_metrics(&state, device(serve_tcp(1).await), None).await.err().unwrap();
+        assert_eq!(error.kind(), "reset");
+
+        let state = ModbusState { modbus_retries: 1, ..state };
+        let reading = ModbusTcp.read_metrics(&state, device(serve_tcp(1).await), None).await.unwrap();
+        assert_eq!(values(&reading).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn modbus_rtu_reads_registers() {
+        let device = device(serve_rtu().await);
+        let state = state(Backends::new(), 0);
+
+        let reading = ModbusRtu.read_metrics(&state, device, None).await.unwrap();
+        assert_eq!(
+            values(&reading),
+            [("fems_state".to_string(), 222.0), ("fems_ess_soc_percent".to_string(), 302.0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn jsonrpc_reads_channels() {
+        let host = serve_jsonrpc(json!({"_sum/State": 1, "_sum/EssSoc": null}));
+        let client = JsonRpcClient::new(Some(&format!("http://{host}/jsonrpc")), host.ip(), None).unwrap();
+        let state = state(Backends::new(), 0);
+
+        let reading = JsonRpc::new(client).read_metrics(&state, device(host), None).await.unwrap();
+        assert_eq!(values(&reading), [("fems_state".to_string(), 1.0)]);
+        assert_eq!(reading.groups[0].0, "state");
+    }
+
+    #[tokio::test]
+    async fn due_groups_limit_the_read() {
+        let device = device(serve_tcp(0).await);
+        let state = state(Backends::new(), 0);
+        let due = HashSet::from(["ess".to_string()]);
+
+        let reading = ModbusTcp.read_metrics(&state, device, Some(&due)).await.unwrap();
+        assert_eq!(values(&reading), [("fems_ess_soc_percent".to_string(), 302.0)]);
+    }
+}
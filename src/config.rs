@@ -0,0 +1,360 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    error::Error,
+    fs,
+    net::SocketAddr,
+    path::Path,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    backend::BackendKind,
+    credentials::Credentials,
+    custom::CustomRegister,
+    downsample::{DownsamplingConfig, SUFFIXES},
+    exposition::{scale_energy, Kilowatthours, NumberFormat},
+    faults::FaultRegister,
+    io::IoPoint,
+    jsonrpc::JsonRpcClient,
+    locate::Locations,
+    modules::BatteryModules,
+    modbus::{default_unit_id, deserialize_host, metric_names, Device, MissingFemsId, Sample, Series, SignConvention, Value, STALE_METRIC},
+    registers::{check_metric_name, metric_table, Group, LabelOverride, MetricKind, OPTIONAL_GROUPS},
+    relabel::{relabel, RelabelRule},
+    tenants::TenantConfig,
+};
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// FEMS instances that are polled in the background
+    #[serde(default)]
+    pub targets: Vec<Target>,
+    pub otlp: Option<OtlpConfig>,
+    /// Changes to the label sets of the built-in metrics
+    #[serde(default)]
+    pub labels: Vec<LabelOverride>,
+    /// Bitfield registers exported as `fems_fault` series
+    #[serde(default)]
+    pub faults: Vec<FaultRegister>,
+    /// Batteries whose towers and modules are read individually
+    #[serde(default)]
+    pub batteries: Vec<BatteryModules>,
+    /// Whether energy is also exported in kWh: `off`, `alongside` or `instead`
+    #[serde(default)]
+    pub kilowatthours: Kilowatthours,
+    /// How values are written in the text format, e.g. `precision: 3`
+    #[serde(default)]
+    pub number_format: NumberFormat,
+    /// Label of scrapes without `fems_id` of devices that aren't configured: `host` or `omit`
+    #[serde(default)]
+    pub missing_fems_id: MissingFemsId,
+    /// Rename rules applied to /metrics and /stream output
+    #[serde(default)]
+    pub relabel: Vec<RelabelRule>,
+    /// Relays and digital inputs, read from coils and discrete inputs
+    #[serde(default)]
+    pub io: Vec<IoPoint>,
+    /// Gauges of the targets polled frequently and exported as min/max/avg per scrape
+    pub downsampling: Option<DownsamplingConfig>,
+    /// Customers sharing the exporter, each only sees its own targets
+    #[serde(default)]
+    pub tenants: Vec<TenantConfig>,
+    /// Where the entries are in the config file, for the problems found by [`Config::check`]
+    #[serde(skip)]
+    pub locations: Locations,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    #[serde(deserialize_with = "deserialize_host")]
+    pub host: SocketAddr,
+    #[serde(default = "default_unit_id")]
+    pub unit_id: u8,
+    pub fems_id: String,
+    /// How the target is read: `modbus_tcp`, `modbus_rtu` via a serial gateway, `jsonrpc`, or
+    /// `simulated` with synthetic values
+    #[serde(default)]
+    pub backend: BackendKind,
+    /// Credentials for backends that require them, Modbus doesn't
+    pub auth: Option<Credentials>,
+    /// OpenEMS JSON-RPC endpoint, `http://<host>/jsonrpc` by default
+    pub jsonrpc_url: Option<String>,
+    /// Whether to export the production and consumption predictions, read via JSON-RPC
+    #[serde(default)]
+    pub predictions: bool,
+    /// Tenant owning the target, only it and admins can read it
+    pub tenant: Option<String>,
+    /// Optional register groups to read from this target: `heatpump` or `peakshaving`
+    #[serde(default)]
+    pub optional_groups: Vec<String>,
+    /// `inverted` if the firmware signs grid and ESS power the other way around than OpenEMS,
+    /// they are flipped to be exported like those of other targets
+    #[serde(default)]
+    pub sign_convention: SignConvention,
+    /// Registers only read from this target, in addition to the metric table
+    #[serde(default)]
+    pub registers: Vec<CustomRegister>,
+}
+
+impl Target {
+    pub fn device(&self) -> Device {
+        Device {
+            host: self.host,
+            unit_id: self.unit_id,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OtlpConfig {
+    /// gRPC endpoint of the OpenTelemetry collector
+    #[serde(default = "default_otlp_endpoint")]
+    pub endpoint: String,
+    /// Seconds between polling the targets and exporting the results
+    #[serde(default = "default_otlp_interval")]
+    pub interval: u64,
+    /// Seconds between polling specific register groups, if they differ from `interval`
+    #[serde(default)]
+    pub group_intervals: BTreeMap<String, u64>,
+    /// Additional resource attributes, e.g. `deployment.environment`
+    #[serde(default)]
+    pub resource: HashMap<String, String>,
+    /// Whether to also export a span per scrape of /metrics, linked from the scrape duration
+    /// histogram as exemplar
+    #[serde(default)]
+    pub tracing: bool,
+}
+
+fn default_otlp_endpoint() -> String {
+    "http://localhost:4317".to_string()
+}
+
+fn default_otlp_interval() -> u64 {
+    30
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, Box<dyn Error>> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("unable to read config file {}: {e}", path.display()))?;
+
+        let mut config: Config = serde_yaml::from_str(&content)
+            .map_err(|e| format!("invalid config file {}: {e}", path.display()))?;
+        config.locations = Locations::scan(&content);
+
+        Ok(config)
+    }
+
+    /// Finds problems that span several entries and can't be caught while parsing.
+    ///
+    /// Each problem names the entry it was found in and, if the config was loaded from a file,
+    /// its line and column.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut problem = |path: &str, message: String| {
+            problems.push(format!("{path}: {message}{}", self.locations.at(path)));
+        };
+
+        let mut devices = HashSet::new();
+        let mut fems_ids = HashSet::new();
+        // Devices on one host share a connection, so they have to frame Modbus the same way
+        let mut framings = HashMap::new();
+        for (i, target) in self.targets.iter().enumerate() {
+            let path = format!("targets[{i}]");
+            if !devices.insert(target.device()) {
+                problem(&path, format!("{} unit {} is configured more than once", target.host, target.unit_id));
+            }
+            if !fems_ids.insert(&target.fems_id) {
+                problem(&format!("{path}.fems_id"), format!("fems_id {:?} is used more than once", target.fems_id));
+            }
+            if matches!(target.backend, BackendKind::ModbusTcp | BackendKind::ModbusRtu) {
+                if let Some(other) = framings.insert(target.host, target.backend) {
+                    if other != target.backend {
+                        problem(&path, format!("{} is also read with a different Modbus backend", target.host));
+                    }
+                }
+            }
+            if let Some(tenant) = &target.tenant {
+                if !self.tenants.iter().any(|t| t.name == *tenant) {
+                    problem(&format!("{path}.tenant"), format!("unknown tenant {tenant:?}"));
+                }
+            }
+            for (j, group) in target.optional_groups.iter().enumerate() {
+                if !OPTIONAL_GROUPS.iter().any(|g| g.name == group) {
+                    problem(&format!("{path}.optional_groups[{j}]"), format!("unknown group {group:?}"));
+                }
+            }
+            if target.predictions || target.backend == BackendKind::JsonRpc {
+                if let Err(e) = JsonRpcClient::new(target.jsonrpc_url.as_deref(), target.host.ip(), target.auth.as_ref()) {
+                    problem(&path, e.to_string());
+                }
+            }
+        }
+
+        let mut names = HashSet::new();
+        let mut tokens = HashSet::new();
+        for (i, tenant) in self.tenants.iter().enumerate() {
+            if !names.insert(&tenant.name) {
+                problem(&format!("tenants[{i}].name"), format!("name {:?} is used more than once", tenant.name));
+            }
+            if !tokens.insert(tenant.token.expose()) {
+                problem(&format!("tenants[{i}].token"), "token is used by another tenant".to_string());
+            }
+        }
+
+        let table = metric_table(self);
+
+        for (i, label_override) in self.labels.iter().enumerate() {
+            if let Some(metric) = &label_override.metric {
+                if !table.iter().flat_map(|g| &g.metrics).any(|m| m.name == *metric) {
+                    problem(&format!("labels[{i}].metric"), format!("unknown metric {metric:?}"));
+                }
+            }
+        }
+
+        if let Some(otlp) = &self.otlp {
+            if otlp.interval == 0 {
+                problem("otlp.interval", "must be at least 1 second".to_string());
+            }
+            for (group, interval) in &otlp.group_intervals {
+                let path = format!("otlp.group_intervals.{group}");
+                if !table.iter().any(|g| g.name == *group) {
+                    problem(&path, format!("unknown register group {group:?}"));
+                }
+                if *interval == 0 {
+                    problem(&path, "must be at least 1 second".to_string());
+                }
+            }
+        }
+
+        if let Some(downsampling) = &self.downsampling {
+            if downsampling.interval == 0 {
+                problem("downsampling.interval", "must be at least 1 second".to_string());
+            }
+            if self.targets.is_empty() {
+                problem("downsampling", "only configured targets are polled, but there are none".to_string());
+            }
+            for (i, metric) in downsampling.metrics.iter().enumerate() {
+                let path = format!("downsampling.metrics[{i}]");
+                match table.iter().flat_map(|g| &g.metrics).find(|m| m.name == *metric) {
+                    None => problem(&path, format!("unknown metric {metric:?}")),
+                    Some(m) if m.kind != MetricKind::Gauge => problem(&path, format!("{metric:?} is not a gauge")),
+                    Some(_) => {}
+                }
+            }
+        }
+
+        // Find the series the overrides and rules merge, series of different targets don't collide
+        let series = self.exported_series(&table);
+        let mut duplicates = Vec::new();
+        let mut invalid = HashSet::new();
+        let mut seen: HashMap<_, Vec<&Option<Vec<Device>>>> = HashMap::new();
+        for ExportedSeries { metric, series: Series { name, labels, .. }, devices, .. } in &series {
+            // Names with capture groups of relabel rules are only known once they are applied
+            if check_metric_name(name).is_err() && invalid.insert((metric, name)) {
+                problem("relabel", format!("{metric} is renamed to the invalid metric name {name:?}"));
+            }
+            // Renamed onto a label the metric already has
+            for (i, (label, _)) in labels.iter().enumerate() {
+                if labels[..i].iter().any(|(l, _)| l == label) && invalid.insert((metric, label)) {
+                    problem("relabel", format!("{metric} has the label {label:?} twice after relabeling"));
+                }
+            }
+
+            let mut labels: Vec<_> = labels.iter().collect();
+            labels.sort();
+
+            let others = seen.entry((name, labels.clone())).or_default();
+            let collides = others.iter().any(|other| match (other, devices) {
+                (Some(other), Some(devices)) => other.iter().any(|d| devices.contains(d)),
+                _ => true,
+            });
+            others.push(devices);
+            if collides {
+                let labels: Vec<String> = labels.iter().map(|(l, v)| format!("{l}={v:?}")).collect();
+                duplicates.push(format!("duplicate series {name}{{{}}}", labels.join(", ")));
+            }
+        }
+
+        problems.extend(duplicates);
+        problems
+    }
+
+    /// One series per label set the config exports, with placeholder values and without fems_id.
+    pub fn exported_series(&self, table: &[Group]) -> Vec<ExportedSeries> {
+        let mut exported = Vec::new();
+
+        for group in table.iter() {
+            for metric in &group.metrics {
+                let mut add = |mut labels: Vec<(String, String)>| {
+                    if group.modules.is_some() {
+                        labels.push(("tower".to_string(), "0".to_string()));
+                        labels.push(("module".to_string(), "0".to_string()));
+                    }
+                    exported.push((metric.name.clone(), metric.kind, labels, group.devices.clone()));
+                };
+
+                if metric.bits.is_empty() {
+                    add(metric.labels.clone());
+                }
+                for (_, code) in &metric.bits {
+                    let mut labels = metric.labels.clone();
+                    labels.push(("code".to_string(), code.clone()));
+                    add(labels);
+                }
+            }
+        }
+
+        if let Some(downsampling) = &self.downsampling {
+            let aggregated: Vec<_> = exported
+                .iter()
+                .filter(|(name, _, _, _)| downsampling.metrics.contains(name))
+                .flat_map(|(name, _, labels, devices)| {
+                    SUFFIXES.map(|suffix| (format!("{name}{suffix}"), MetricKind::Gauge, labels.clone(), devices.clone()))
+                })
+                .collect();
+            exported.extend(aggregated);
+        }
+
+        // Derived metrics are exported if the metrics they are derived from are
+        for (name, kind) in metric_names(table) {
+            if name != STALE_METRIC && !exported.iter().any(|(n, _, _, _)| *n == name) {
+                exported.push((name, kind, Vec::new(), None));
+            }
+        }
+
+        // Rendered one by one to keep track of the metric each series comes from
+        exported
+            .into_iter()
+            .flat_map(|(metric, kind, labels, devices)| {
+                // With an empty fems_id, as rules might match on it
+                let sample = Sample {
+                    name: metric.clone(),
+                    labels,
+                    value: Value::U16(0),
+                };
+                let mut series = scale_energy(vec![sample.to_series("")], self.kilowatthours);
+                relabel(&self.relabel, &mut series);
+
+                series.into_iter().map(move |mut series| {
+                    series.labels.retain(|(l, _)| l != "fems_id");
+                    ExportedSeries { metric: metric.clone(), kind, series, devices: devices.clone() }
+                })
+            })
+            .collect()
+    }
+}
+
+/// A series as exported after relabeling, see [`Config::exported_series`].
+pub struct ExportedSeries {
+    /// Name of the metric in the metric table, before relabeling
+    pub metric: String,
+    pub kind: MetricKind,
+    pub series: Series,
+    /// Targets the series is read from, all if `None`
+    pub devices: Option<Vec<Device>>,
+}
@@ -0,0 +1,180 @@
+//! Credentials of a target for backends that require them, e.g. the OpenEMS JSON-RPC API.
+//!
+//! Secrets are never written out: their `Debug` output is redacted and they can't be
+//! serialized, so neither logs nor /targets can contain them.
+
+use std::{env, fmt, fs, path::PathBuf};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+
+/// Value that must not be logged, read from the config with `${VAR}` interpolation or from a file.
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "RawSecret")]
+pub struct Secret(String);
+
+#[derive(Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+enum RawSecret {
+    /// Literal value, `${VAR}` is replaced by the environment variable `VAR` and `$$` by `$`
+    Value(String),
+    /// File containing only the secret, e.g. a Docker or systemd credential
+    File { file: PathBuf },
+}
+
+impl Secret {
+    /// The secret itself, only to be passed on to the device.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl TryFrom<RawSecret> for Secret {
+    type Error = String;
+
+    fn try_from(raw: RawSecret) -> Result<Self, Self::Error> {
+        match raw {
+            RawSecret::Value(value) => interpolate(&value).map(Secret),
+            RawSecret::File { file } => {
+                let content = fs::read_to_string(&file)
+                    .map_err(|e| format!("unable to read secret file {}: {e}", file.display()))?;
+                // Files usually end with a newline that isn't part of the secret
+                Ok(Secret(content.trim_end_matches(['\r', '\n']).to_string()))
+            }
+        }
+    }
+}
+
+/// Replaces `${VAR}` by the value of the environment variable and `$$` by a literal `$`.
+fn interpolate(value: &str) -> Result<String, String> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        if let Some(after) = rest.strip_prefix('$') {
+            result.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let end = after.find('}').ok_or_else(|| format!("unterminated ${{ in {value:?}"))?;
+            let name = &after[..end];
+            let variable = env::var(name).map_err(|_| format!("environment variable {name} is not set"))?;
+            result.push_str(&variable);
+            rest = &after[end + 1..];
+        } else {
+            result.push('$');
+        }
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+/// How to authenticate at a target, either with a user and password or with an API key.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "RawCredentials")]
+pub enum Credentials {
+    Password { username: String, password: Secret },
+    ApiKey(Secret),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCredentials {
+    username: Option<String>,
+    password: Option<Secret>,
+    api_key: Option<Secret>,
+}
+
+impl TryFrom<RawCredentials> for Credentials {
+    type Error = String;
+
+    fn try_from(raw: RawCredentials) -> Result<Self, Self::Error> {
+        match raw {
+            RawCredentials { username: Some(username), password: Some(password), api_key: None } => {
+                Ok(Credentials::Password { username, password })
+            }
+            RawCredentials { username: None, password: None, api_key: Some(api_key) } => {
+                Ok(Credentials::ApiKey(api_key))
+            }
+            _ => Err("expected either username and password or api_key".to_string()),
+        }
+    }
+}
+
+impl Credentials {
+    /// Name of the method for /targets, which must not show the credentials themselves.
+    pub fn method(&self) -> &'static str {
+        match self {
+            Credentials::Password { .. } => "password",
+            Credentials::ApiKey(_) => "api_key",
+        }
+    }
+
+    /// Value of the HTTP Authorization header.
+    pub fn authorization(&self) -> String {
+        match self {
+            Credentials::Password { username, password } => {
+                format!("Basic {}", STANDARD.encode(format!("{username}:{}", password.expose())))
+            }
+            Credentials::ApiKey(key) => format!("Bearer {}", key.expose()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials(yaml: &str) -> Result<Credentials, serde_yaml::Error> {
+        serde_yaml::from_str(yaml)
+    }
+
+    #[test]
+    fn variables_are_interpolated() {
+        env::set_var("FEMS_EXPORTER_TEST_PASSWORD", "s3cret");
+
+        assert_eq!(interpolate("${FEMS_EXPORTER_TEST_PASSWORD}!").unwrap(), "s3cret!");
+        assert_eq!(interpolate("$$5 and $ alone").unwrap(), "$5 and $ alone");
+        assert!(interpolate("${FEMS_EXPORTER_TEST_UNSET}").is_err());
+        assert!(interpolate("${FEMS_EXPORTER_TEST_PASSWORD").is_err());
+    }
+
+    #[test]
+    fn secrets_are_read_from_files() {
+        let path = env::temp_dir().join(format!("fems_exporter_secret_{}", std::process::id()));
+        fs::write(&path, "from-file\n").unwrap();
+
+        let secret: Secret = serde_yaml::from_str(&format!("file: {}", path.display())).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(secret.expose(), "from-file");
+    }
+
+    #[test]
+    fn credentials_are_a_password_or_an_api_key() {
+        let password = credentials("{username: admin, password: admin}").unwrap();
+        assert_eq!(password.method(), "password");
+        assert_eq!(password.authorization(), "Basic YWRtaW46YWRtaW4=");
+
+        let api_key = credentials("{api_key: key}").unwrap();
+        assert_eq!(api_key.method(), "api_key");
+        assert_eq!(api_key.authorization(), "Bearer key");
+
+        assert!(credentials("{username: admin}").is_err());
+        assert!(credentials("{username: admin, password: admin, api_key: key}").is_err());
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        let password = credentials("{username: admin, password: hunter2}").unwrap();
+        assert!(!format!("{password:?}").contains("hunter2"));
+    }
+}
@@ -0,0 +1,140 @@
+//! Registers added to single targets in the config, e.g. for a special meter at one site.
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use crate::{
+    config::Target,
+    registers::{check_metric_name, default_address, deserialize_label_map, Group, MetricDef, MetricKind, ModbusType},
+};
+
+/// A register read from one target in addition to the metric table.
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "RawCustomRegister")]
+pub struct CustomRegister {
+    component: String,
+    metric: MetricDef,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCustomRegister {
+    /// Component the register belongs to, `address` is relative to its block unless it's `_sum`
+    #[serde(default = "default_component")]
+    component: String,
+    address: u16,
+    #[serde(rename = "type")]
+    modbus_type: ModbusType,
+    name: String,
+    /// `gauge` or `counter`
+    #[serde(default = "default_kind")]
+    kind: MetricKind,
+    #[serde(default, deserialize_with = "deserialize_label_map")]
+    labels: BTreeMap<String, String>,
+}
+
+fn default_component() -> String {
+    "_sum".to_string()
+}
+
+fn default_kind() -> MetricKind {
+    MetricKind::Gauge
+}
+
+impl TryFrom<RawCustomRegister> for CustomRegister {
+    type Error = String;
+
+    fn try_from(raw: RawCustomRegister) -> Result<Self, Self::Error> {
+        let RawCustomRegister { component, address, modbus_type, name, kind, labels } = raw;
+        check_metric_name(&name)?;
+
+        match modbus_type {
+            ModbusType::Bitfield => return Err(format!("register {name} can't be a bitfield, configure it in faults")),
+            ModbusType::Coil | ModbusType::DiscreteInput => {
+                return Err(format!("register {name} must be a register, configure coils and discrete inputs in io"))
+            }
+            _ => {}
+        }
+        if let Some(base) = default_address(&component).filter(|base| address < *base) {
+            return Err(format!("register {name} at {address} is before the start of component {component} at {base}"));
+        }
+
+        Ok(CustomRegister {
+            component,
+            metric: MetricDef {
+                name,
+                labels: labels.into_iter().collect(),
+                address,
+                modbus_type,
+                kind,
+                bits: Vec::new(),
+            },
+        })
+    }
+}
+
+/// Builds one register group per target and component with custom registers, each only read
+/// from its target.
+pub fn custom_groups(targets: &[Target]) -> Vec<Group> {
+    let mut groups: Vec<Group> = Vec::new();
+
+    for target in targets {
+        let first = groups.len();
+        for register in &target.registers {
+            match groups[first..].iter_mut().find(|g| g.component == register.component) {
+                Some(group) => group.metrics.push(register.metric.clone()),
+                None => groups.push(Group {
+                    name: format!("custom_{}_{}", target.fems_id, register.component),
+                    component: register.component.clone(),
+                    metrics: vec![register.metric.clone()],
+                    modules: None,
+                    devices: Some(vec![target.device()]),
+                }),
+            }
+        }
+    }
+
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets(yaml: &str) -> Result<Vec<Target>, serde_yaml::Error> {
+        serde_yaml::from_str(yaml)
+    }
+
+    #[test]
+    fn registers_are_grouped_per_target_and_component() {
+        let targets = targets(
+            "[{host: '10.0.0.1', fems_id: home, registers: [
+                 {address: 400, type: f32, name: fems_heater_power_watts},
+                 {component: meter2, address: 10, type: u16, name: fems_meter2_state},
+                 {address: 402, type: f64, name: fems_heater_energy_watthours_total, kind: counter}]},
+              {host: '10.0.0.2', fems_id: other, registers: [{address: 400, type: u16, name: fems_other_state}]}]",
+        )
+        .unwrap();
+        let groups = custom_groups(&targets);
+
+        let names: Vec<(&str, usize)> = groups.iter().map(|g| (g.name.as_str(), g.metrics.len())).collect();
+        assert_eq!(names, [("custom_home__sum", 2), ("custom_home_meter2", 1), ("custom_other__sum", 1)]);
+        assert!(groups[0].applies_to(targets[0].device()));
+        assert!(!groups[0].applies_to(targets[1].device()));
+        assert!(groups[0].metrics[1].kind == MetricKind::Counter);
+    }
+
+    #[test]
+    fn invalid_registers_are_rejected() {
+        let register =
+            |register: &str| targets(&format!("[{{host: '10.0.0.1', fems_id: home, registers: [{register}]}}]"));
+
+        assert!(register("{address: 400, type: u16, name: fems_x}").is_ok());
+        assert!(register("{address: 400, type: u16, name: 'fems-x'}").is_err());
+        assert!(register("{address: 400, type: bitfield, name: fems_x}").is_err());
+        assert!(register("{address: 400, type: coil, name: fems_x}").is_err());
+        // Before the first register of _sum
+        assert!(register("{address: 100, type: u16, name: fems_x}").is_err());
+    }
+}
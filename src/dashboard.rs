@@ -0,0 +1,269 @@
+//! Grafana dashboard generated from the metrics the config exports.
+
+use serde_json::{json, Value};
+
+use crate::{
+    config::{Config, ExportedSeries},
+    registers::{metric_table, MetricKind},
+};
+
+/// Rows of the dashboard, in the order they are shown.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Row {
+    PowerFlows,
+    Battery,
+    Energy,
+    Status,
+}
+
+impl Row {
+    const ALL: [Row; 4] = [Row::PowerFlows, Row::Battery, Row::Energy, Row::Status];
+
+    fn of(metric: &str, kind: MetricKind) -> Row {
+        if kind == MetricKind::Counter {
+            Row::Energy
+        } else if metric.contains("_watts") {
+            Row::PowerFlows
+        } else if metric.starts_with("fems_ess_") || metric.starts_with("fems_battery_") {
+            Row::Battery
+        } else {
+            Row::Status
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            Row::PowerFlows => "Power flows",
+            Row::Battery => "Battery",
+            Row::Energy => "Energy",
+            Row::Status => "Status",
+        }
+    }
+}
+
+/// A metric name as exported, with everything needed to build its panel.
+struct PanelMetric {
+    name: String,
+    metric: String,
+    kind: MetricKind,
+    /// Label names of its series, used for the legend
+    labels: Vec<String>,
+}
+
+const PANEL_HEIGHT: u32 = 8;
+const PANEL_WIDTH: u32 = 12;
+
+/// Renders a dashboard with one panel per exported metric name, grouped into rows.
+pub fn render(config: &Config, title: &str) -> Value {
+    let table = metric_table(config);
+    let exported = config.exported_series(&table);
+
+    let mut metrics: Vec<PanelMetric> = Vec::new();
+    for ExportedSeries { metric, kind, series, .. } in exported {
+        let labels = series.labels.iter().map(|(l, _)| l.clone());
+        match metrics.iter_mut().find(|m| m.name == series.name) {
+            Some(existing) => {
+                for label in labels {
+                    if !existing.labels.contains(&label) {
+                        existing.labels.push(label);
+                    }
+                }
+            }
+            None => metrics.push(PanelMetric {
+                name: series.name,
+                metric,
+                kind,
+                labels: labels.collect(),
+            }),
+        }
+    }
+
+    let mut panels = Vec::new();
+    let mut y = 0;
+
+    for row in Row::ALL {
+        let in_row: Vec<&PanelMetric> = metrics.iter().filter(|m| Row::of(&m.metric, m.kind) == row).collect();
+        if in_row.is_empty() {
+            continue;
+        }
+
+        panels.push(json!({
+            "type": "row",
+            "title": row.title(),
+            "collapsed": false,
+            "gridPos": {"h": 1, "w": 24, "x": 0, "y": y},
+            "panels": [],
+        }));
+        y += 1;
+
+        // Totals of all power flows side by side, before the per-metric panels
+        let mut row_panels = Vec::new();
+        if row == Row::PowerFlows {
+            let totals: Vec<&PanelMetric> = in_row.iter().copied().filter(|m| m.metric.ends_with("_total")).collect();
+            if !totals.is_empty() {
+                let targets = totals
+                    .iter()
+                    .map(|m| (selector(&m.name), format!("{{{{fems_id}}}} {}", humanize(&m.metric))))
+                    .collect();
+                row_panels.push(panel("timeseries", "Power flows", "watt", targets));
+            }
+        }
+        for metric in in_row {
+            row_panels.push(metric_panel(metric));
+        }
+
+        // Two panels side by side
+        let count = row_panels.len() as u32;
+        for (i, mut panel) in (0..).zip(row_panels) {
+            panel["gridPos"] = json!({
+                "h": PANEL_HEIGHT,
+                "w": PANEL_WIDTH,
+                "x": (i % 2) * PANEL_WIDTH,
+                "y": y + (i / 2) * PANEL_HEIGHT,
+            });
+            panels.push(panel);
+        }
+        y += count.div_ceil(2) * PANEL_HEIGHT;
+    }
+
+    // Any exported metric carries the fems_id label
+    let variable_source = metrics.first().map(|m| m.name.as_str()).unwrap_or("fems_state");
+
+    json!({
+        "title": title,
+        "tags": ["fems"],
+        "timezone": "browser",
+        "schemaVersion": 38,
+        "refresh": "30s",
+        "time": {"from": "now-24h", "to": "now"},
+        "templating": {
+            "list": [
+                {
+                    "name": "datasource",
+                    "label": "Data source",
+                    "type": "datasource",
+                    "query": "prometheus",
+                },
+                {
+                    "name": "fems_id",
+                    "label": "FEMS",
+                    "type": "query",
+                    "datasource": {"type": "prometheus", "uid": "${datasource}"},
+                    "query": format!("label_values({variable_source}, fems_id)"),
+                    "refresh": 2,
+                    "includeAll": true,
+                    "multi": true,
+                },
+            ],
+        },
+        "panels": panels,
+    })
+}
+
+impl PanelMetric {
+    /// Unit of the exported name, which might be scaled, falling back to the original name.
+    fn unit(&self) -> &'static str {
+        match unit(&self.name) {
+            "none" => unit(&self.metric),
+            unit => unit,
+        }
+    }
+}
+
+fn metric_panel(metric: &PanelMetric) -> Value {
+    let legend = metric
+        .labels
+        .iter()
+        .map(|l| format!("{{{{{l}}}}}"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let legend = if legend.is_empty() { humanize(&metric.metric) } else { legend };
+    let legend = format!("{{{{fems_id}}}} {legend}");
+
+    match metric.kind {
+        MetricKind::Counter => {
+            let expr = format!("increase({}[$__interval])", selector(&metric.name));
+            let mut panel = panel("timeseries", &humanize(&metric.metric), metric.unit(), vec![(expr, legend)]);
+            panel["fieldConfig"]["defaults"]["custom"] = json!({"drawStyle": "bars", "fillOpacity": 80});
+            panel
+        }
+        MetricKind::Gauge if metric.metric.ends_with("_percent") => {
+            let mut panel = panel("gauge", &humanize(&metric.metric), "percent", vec![(selector(&metric.name), legend)]);
+            panel["fieldConfig"]["defaults"]["min"] = json!(0);
+            panel["fieldConfig"]["defaults"]["max"] = json!(100);
+            panel
+        }
+        MetricKind::Gauge => panel(
+            "timeseries",
+            &humanize(&metric.metric),
+            metric.unit(),
+            vec![(selector(&metric.name), legend)],
+        ),
+    }
+}
+
+fn panel(panel_type: &str, title: &str, unit: &str, targets: Vec<(String, String)>) -> Value {
+    let targets: Vec<Value> = targets
+        .into_iter()
+        .enumerate()
+        .map(|(i, (expr, legend))| {
+            json!({
+                "refId": char::from(b'A' + (i % 26) as u8).to_string(),
+                "expr": expr,
+                "legendFormat": legend,
+            })
+        })
+        .collect();
+
+    json!({
+        "type": panel_type,
+        "title": title,
+        "datasource": {"type": "prometheus", "uid": "${datasource}"},
+        "targets": targets,
+        "fieldConfig": {"defaults": {"unit": unit}, "overrides": []},
+    })
+}
+
+fn selector(name: &str) -> String {
+    format!("{name}{{fems_id=~\"$fems_id\"}}")
+}
+
+/// Grafana unit matching the metric name suffix.
+fn unit(metric: &str) -> &'static str {
+    let metric = metric.trim_end_matches("_total");
+
+    if metric.ends_with("_kilowatthours") {
+        "kwatth"
+    } else if metric.ends_with("_watthours") {
+        "watth"
+    } else if metric.ends_with("_watts") {
+        "watt"
+    } else if metric.ends_with("_voltampere") {
+        "voltamp"
+    } else if metric.ends_with("_percent") {
+        "percent"
+    } else if metric.ends_with("_volts") {
+        "volt"
+    } else if metric.ends_with("_celsius") {
+        "celsius"
+    } else {
+        "none"
+    }
+}
+
+/// Panel title for a metric, e.g. "Grid power" for `fems_grid_power_watts`.
+fn humanize(metric: &str) -> String {
+    let words: Vec<&str> = metric
+        .trim_start_matches("fems_")
+        .split('_')
+        .filter(|w| !matches!(*w, "watts" | "watthours" | "kilowatthours" | "percent" | "voltampere" | "volts" | "celsius"))
+        .map(|w| if w == "ess" { "battery" } else { w })
+        .collect();
+
+    let title = words.join(" ");
+    let mut chars = title.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => title,
+    }
+}
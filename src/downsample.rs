@@ -0,0 +1,130 @@
+//! Minimum, maximum and average of frequently polled gauges between two scrapes.
+//!
+//! The configured targets are polled every `interval` seconds in the background and every value
+//! of the selected metrics is aggregated. A scrape of /metrics takes the aggregates, exported as
+//! `<metric>_min`, `<metric>_max` and `<metric>_avg`, and starts a new window. That way spikes
+//! shorter than the scrape interval still show up. A scrape without a poll since the previous
+//! one has no aggregates.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::warn;
+
+use crate::{
+    config::Target,
+    modbus::{read_groups, Device, ModbusState, Sample, Value},
+};
+
+pub const SUFFIXES: [&str; 3] = ["_min", "_max", "_avg"];
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DownsamplingConfig {
+    /// Seconds between two polls of the targets
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// Gauges to aggregate, e.g. `fems_ess_power_watts`
+    pub metrics: Vec<String>,
+}
+
+fn default_interval() -> u64 {
+    1
+}
+
+#[derive(Clone, Copy)]
+pub struct Aggregate {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u32,
+}
+
+impl Aggregate {
+    fn new(value: f64) -> Self {
+        Aggregate { min: value, max: value, sum: value, count: 1 }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Aggregates since the last scrape, by metric name and labels.
+pub type Window = BTreeMap<(String, Vec<(String, String)>), Aggregate>;
+
+/// Windows of all polled devices.
+pub type Windows = Arc<Mutex<HashMap<Device, Window>>>;
+
+/// Polls `targets` every `config.interval` and aggregates the configured metrics.
+pub fn spawn(state: ModbusState, targets: Vec<Target>, config: &DownsamplingConfig) {
+    let metrics: HashSet<String> = config.metrics.iter().cloned().collect();
+    let period = Duration::from_secs(config.interval.max(1));
+
+    tokio::spawn(async move {
+        let mut ticker = interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            ticker.tick().await;
+
+            // Taken from the metric table every time, a reload may have moved the metrics
+            let due: HashSet<String> = state
+                .table()
+                .iter()
+                .filter(|g| g.metrics.iter().any(|m| metrics.contains(&m.name)))
+                .map(|g| g.name.clone())
+                .collect();
+
+            for target in &targets {
+                let device = target.device();
+                if state.throttled(device) {
+                    continue;
+                }
+                let groups = match read_groups(&state, device, &due).await {
+                    Ok(groups) => groups,
+                    Err(e) => {
+                        warn!(fems_id = target.fems_id, kind = e.kind(), "polling for downsampling failed: {e}");
+                        continue;
+                    }
+                };
+
+                let mut windows = state.windows.lock().unwrap();
+                let window = windows.entry(device).or_default();
+                for sample in groups.iter().flat_map(|(_, s)| s).filter(|s| metrics.contains(&s.name)) {
+                    let value = sample.value.as_f64();
+                    window
+                        .entry((sample.name.clone(), sample.labels.clone()))
+                        .and_modify(|a| a.add(value))
+                        .or_insert_with(|| Aggregate::new(value));
+                }
+            }
+        }
+    });
+}
+
+/// Takes the aggregates of `device` collected since the last call, as samples.
+pub fn drain(windows: &Windows, device: Device) -> Vec<Sample> {
+    let window = windows.lock().unwrap().remove(&device).unwrap_or_default();
+
+    let mut samples = Vec::new();
+    for ((name, labels), aggregate) in window {
+        let values = [aggregate.min, aggregate.max, aggregate.sum / f64::from(aggregate.count)];
+        for (suffix, value) in SUFFIXES.into_iter().zip(values) {
+            samples.push(Sample {
+                name: format!("{name}{suffix}"),
+                labels: labels.clone(),
+                value: Value::F64(value),
+            });
+        }
+    }
+    samples
+}
@@ -0,0 +1,173 @@
+//! Errors of reading a device, typed so they can be told apart in logs, metrics and the API.
+
+use std::{fmt, io, net::SocketAddr, time::Duration};
+
+use serde::Serialize;
+
+/// Modbus exception a device answered a request with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Exception {
+    pub code: u8,
+    pub name: &'static str,
+}
+
+/// Exception codes and the messages tokio-modbus formats them with.
+const EXCEPTIONS: [(u8, &str, &str); 9] = [
+    (0x01, "illegal_function", "Illegal function"),
+    (0x02, "illegal_data_address", "Illegal data address"),
+    (0x03, "illegal_data_value", "Illegal data value"),
+    (0x04, "server_device_failure", "Server device failure"),
+    (0x05, "acknowledge", "Acknowledge"),
+    (0x06, "server_device_busy", "Server device busy"),
+    (0x08, "memory_parity_error", "Memory parity error"),
+    (0x0A, "gateway_path_unavailable", "Gateway path unavailable"),
+    (0x0B, "gateway_target_failed", "Gateway target device failed to respond"),
+];
+
+impl Exception {
+    /// The exception `error` stands for, if it is one.
+    ///
+    /// tokio-modbus doesn't expose exception responses as a type, they are only distinguishable
+    /// from I/O errors by their message.
+    pub fn from_io(error: &io::Error) -> Option<Exception> {
+        if error.kind() != io::ErrorKind::Other {
+            return None;
+        }
+
+        let message = error.to_string();
+        EXCEPTIONS
+            .into_iter()
+            .find(|(_, _, description)| message.ends_with(description))
+            .map(|(code, name, _)| Exception { code, name })
+    }
+
+    /// Whether the device doesn't have the register, as opposed to being unable to answer now.
+    pub fn is_unsupported(&self) -> bool {
+        matches!(self.code, 0x01..=0x03)
+    }
+
+    /// Whether the register doesn't exist on the device, which won't change by asking again.
+    pub fn is_permanent(&self) -> bool {
+        self.code == 0x02
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (exception code {})", self.name, self.code)
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ReadError {
+    /// No connection could be opened
+    Connect { host: SocketAddr, message: String },
+    /// The device didn't answer within `--modbus-timeout`, or the operating system gave up first
+    Timeout {
+        /// `None` if the operating system timed out
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seconds: Option<f64>,
+    },
+    /// The device answered with an exception
+    Exception {
+        #[serde(flatten)]
+        exception: Exception,
+    },
+    /// The device answered with something that isn't a valid response
+    Decode { message: String },
+    /// The connection was reset or closed by the device while reading
+    Reset { message: String },
+    /// Reading failed for another reason
+    Io { message: String },
+    /// A request to the OpenEMS JSON-RPC API failed
+    JsonRpc { message: String },
+}
+
+impl ReadError {
+    pub fn timeout(after: Duration) -> Self {
+        ReadError::Timeout { seconds: Some(after.as_secs_f64()) }
+    }
+
+    /// Whether reading again right away might succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, ReadError::Timeout { .. } | ReadError::Reset { .. })
+    }
+
+    /// Short name of the kind of error, used as metric label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ReadError::Connect { .. } => "connect",
+            ReadError::Timeout { .. } => "timeout",
+            ReadError::Exception { .. } => "exception",
+            ReadError::Decode { .. } => "decode",
+            ReadError::Reset { .. } => "reset",
+            ReadError::Io { .. } => "io",
+            ReadError::JsonRpc { .. } => "jsonrpc",
+        }
+    }
+}
+
+impl From<io::Error> for ReadError {
+    fn from(error: io::Error) -> Self {
+        if let Some(exception) = Exception::from_io(&error) {
+            return ReadError::Exception { exception };
+        }
+
+        match error.kind() {
+            io::ErrorKind::InvalidData => ReadError::Decode { message: error.to_string() },
+            io::ErrorKind::TimedOut => ReadError::Timeout { seconds: None },
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof => ReadError::Reset { message: error.to_string() },
+            _ => ReadError::Io { message: error.to_string() },
+        }
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Connect { host, message } => {
+                write!(f, "unable to connect to fems modbus at {host}: {message}")
+            }
+            ReadError::Timeout { seconds: Some(seconds) } => write!(f, "no response from fems modbus within {seconds}s"),
+            ReadError::Timeout { seconds: None } => write!(f, "no response from fems modbus, the connection timed out"),
+            ReadError::Exception { exception } => write!(f, "fems modbus answered with {exception}"),
+            ReadError::Decode { message } => write!(f, "invalid response from fems modbus: {message}"),
+            ReadError::Reset { message } => write!(f, "connection to fems modbus lost: {message}"),
+            ReadError::Io { message } => write!(f, "unable to read modbus input register: {message}"),
+            ReadError::JsonRpc { message } => write!(f, "OpenEMS JSON-RPC request failed: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exceptions_are_told_apart_from_io_errors() {
+        let error = io::Error::other("Modbus function 4: Illegal data address");
+        assert_eq!(Exception::from_io(&error).map(|e| e.name), Some("illegal_data_address"));
+        assert!(matches!(ReadError::from(error), ReadError::Exception { .. }));
+
+        let error = io::Error::other("something else");
+        assert_eq!(Exception::from_io(&error), None);
+        assert_eq!(ReadError::from(error).kind(), "io");
+    }
+
+    #[test]
+    fn timeouts_of_the_operating_system_have_no_duration() {
+        let error = ReadError::from(io::Error::from(io::ErrorKind::TimedOut));
+        assert!(error.is_transient());
+        assert!(!error.to_string().contains("0s"));
+        assert_eq!(serde_json::to_value(&error).unwrap(), serde_json::json!({"kind": "timeout"}));
+
+        let error = ReadError::timeout(Duration::from_millis(1500));
+        assert_eq!(error.to_string(), "no response from fems modbus within 1.5s");
+    }
+}
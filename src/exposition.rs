@@ -0,0 +1,303 @@
+//! Prometheus text format, shared by /metrics and the Pushgateway.
+
+use std::{convert::Infallible, fmt::Write};
+
+use axum::body::Body;
+use bytes::{Bytes, BytesMut};
+use futures::stream;
+use serde::Deserialize;
+
+use crate::modbus::{Series, Value};
+
+/// Whether energy is also exported in kWh, like the FEMS UI shows it.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kilowatthours {
+    #[default]
+    Off,
+    /// Export `*_kilowatthours` series next to the `*_watthours` ones
+    Alongside,
+    /// Export `*_kilowatthours` series only
+    Instead,
+}
+
+/// Adds or substitutes kWh series for every Wh series, depending on `mode`.
+pub fn scale_energy(series: Vec<Series>, mode: Kilowatthours) -> Vec<Series> {
+    if mode == Kilowatthours::Off {
+        return series;
+    }
+
+    let mut scaled = Vec::with_capacity(series.len());
+    for s in series {
+        if !s.name.contains("_watthours") {
+            scaled.push(s);
+            continue;
+        }
+
+        let kilowatthours = Series {
+            name: s.name.replace("_watthours", "_kilowatthours"),
+            labels: s.labels.clone(),
+            value: Value::F64(s.value.as_f64() / 1000.0),
+        };
+        if mode == Kilowatthours::Alongside {
+            scaled.push(s);
+        }
+        scaled.push(kilowatthours);
+    }
+    scaled
+}
+
+/// How sample values are written.
+///
+/// Floats are written as plain decimals, never in scientific notation, and infinities and NaN
+/// are spelled the way Prometheus parses them.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NumberFormat {
+    /// Decimal places floats are rounded to, trailing zeros are dropped. Unset writes the
+    /// shortest decimal that reads back as the same float.
+    pub precision: Option<usize>,
+}
+
+impl NumberFormat {
+    pub fn write(self, out: &mut impl Write, value: &Value) -> std::fmt::Result {
+        match *value {
+            Value::U16(v) => write!(out, "{v}"),
+            Value::Bool(v) => write!(out, "{}", u8::from(v)),
+            // Converting to f64 would add digits the f32 never had
+            Value::F32(v) if self.precision.is_none() && v.is_finite() => write!(out, "{v}"),
+            Value::F32(v) => self.write_float(out, f64::from(v)),
+            Value::F64(v) => self.write_float(out, v),
+        }
+    }
+
+    fn write_float(self, out: &mut impl Write, value: f64) -> std::fmt::Result {
+        if value.is_nan() {
+            return out.write_str("NaN");
+        }
+        if value.is_infinite() {
+            return out.write_str(if value > 0.0 { "+Inf" } else { "-Inf" });
+        }
+
+        let Some(precision) = self.precision else {
+            return write!(out, "{value}");
+        };
+        let rounded = format!("{value:.precision$}");
+        let trimmed = match rounded.contains('.') {
+            true => rounded.trim_end_matches('0').trim_end_matches('.'),
+            false => &rounded,
+        };
+        // Values rounded to zero keep their sign otherwise
+        match trimmed {
+            "-0" => out.write_str("0"),
+            trimmed => out.write_str(trimmed),
+        }
+    }
+}
+
+/// Writes `value` as OpenMetrics canonically writes floats, e.g. in `le`: like [`NumberFormat`]
+/// without a precision, but integral values keep a `.0`.
+pub fn write_canonical(out: &mut impl Write, value: f64) -> std::fmt::Result {
+    let mut written = String::new();
+    NumberFormat::default().write_float(&mut written, value)?;
+    if value.is_finite() && !written.contains('.') {
+        written.push_str(".0");
+    }
+    out.write_str(&written)
+}
+
+/// Bytes a streamed body is flushed after, large scrapes are sent in chunks of about this size.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Writes series in the text format into a buffer that is reused across chunks.
+pub struct Writer {
+    buffer: BytesMut,
+    format: NumberFormat,
+}
+
+impl Writer {
+    pub fn new(format: NumberFormat) -> Self {
+        Writer {
+            buffer: BytesMut::new(),
+            format,
+        }
+    }
+
+    pub fn write(&mut self, Series { name, labels, value }: &Series) {
+        // Writing into a BytesMut can't fail
+        let _ = write!(self.buffer, "{name}");
+        for (i, (label, label_value)) in labels.iter().enumerate() {
+            let separator = if i == 0 { "{" } else { ", " };
+            let _ = write!(self.buffer, "{separator}{label} = \"");
+            let _ = write_label_value(&mut self.buffer, label_value);
+            let _ = write!(self.buffer, "\"");
+        }
+        // Series without labels, e.g. if `fems_id` is omitted, have no braces
+        let _ = write!(self.buffer, "{} ", if labels.is_empty() { "" } else { "}" });
+        let _ = self.format.write(&mut self.buffer, value);
+        let _ = writeln!(self.buffer);
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Takes what has been written so far, keeping the allocation for the following writes.
+    pub fn take(&mut self) -> Bytes {
+        self.buffer.split().freeze()
+    }
+}
+
+/// Writes `value` with backslashes, double quotes and line feeds escaped, as label values are
+/// written in the text format.
+fn write_label_value(out: &mut impl Write, value: &str) -> std::fmt::Result {
+    for c in value.chars() {
+        match c {
+            '\\' => out.write_str("\\\\")?,
+            '"' => out.write_str("\\\"")?,
+            '\n' => out.write_str("\\n")?,
+            c => out.write_char(c)?,
+        }
+    }
+    Ok(())
+}
+
+pub fn render(series: &[Series], format: NumberFormat) -> String {
+    let mut writer = Writer::new(format);
+    for s in series {
+        writer.write(s);
+    }
+    String::from_utf8_lossy(&writer.take()).into_owned()
+}
+
+/// Response body that renders `series` while it is sent, in chunks of [`CHUNK_SIZE`].
+pub fn stream(series: Vec<Series>, format: NumberFormat) -> Body {
+    let chunks = stream::unfold((series.into_iter(), Writer::new(format)), |(mut series, mut writer)| async move {
+        for s in series.by_ref() {
+            writer.write(&s);
+            if writer.len() >= CHUNK_SIZE {
+                let chunk = writer.take();
+                return Some((Ok::<_, Infallible>(chunk), (series, writer)));
+            }
+        }
+
+        // The rest, then the end of the body
+        match writer.len() {
+            0 => None,
+            _ => {
+                let chunk = writer.take();
+                Some((Ok(chunk), (series, writer)))
+            }
+        }
+    });
+
+    Body::from_stream(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn energy() -> Vec<Series> {
+        let series = |name: &str, value| Series {
+            name: name.to_string(),
+            labels: vec![("phase".to_string(), "l1".to_string())],
+            value,
+        };
+        vec![
+            series("fems_grid_buy_active_energy_watthours_total", Value::F64(12500.0)),
+            series("fems_grid_power_watts", Value::F32(230.0)),
+        ]
+    }
+
+    fn names_and_values(series: &[Series]) -> Vec<(&str, f64)> {
+        series.iter().map(|s| (s.name.as_str(), s.value.as_f64())).collect()
+    }
+
+    fn written(value: Value, precision: Option<usize>) -> String {
+        let mut out = String::new();
+        NumberFormat { precision }.write(&mut out, &value).unwrap();
+        out
+    }
+
+    #[test]
+    fn special_floats_are_spelled_like_prometheus() {
+        assert_eq!(written(Value::F64(f64::NAN), None), "NaN");
+        assert_eq!(written(Value::F64(f64::INFINITY), None), "+Inf");
+        assert_eq!(written(Value::F64(f64::NEG_INFINITY), Some(2)), "-Inf");
+        assert_eq!(written(Value::F32(f32::NAN), None), "NaN");
+        assert_eq!(written(Value::F32(f32::NEG_INFINITY), None), "-Inf");
+    }
+
+    #[test]
+    fn floats_are_never_scientific() {
+        assert_eq!(written(Value::F64(1e21), None), "1000000000000000000000");
+        assert_eq!(written(Value::F64(1.5e-7), None), "0.00000015");
+        // Without the digits the f64 of the same value would add
+        assert_eq!(written(Value::F32(0.1), None), "0.1");
+    }
+
+    #[test]
+    fn precision_rounds_and_trims() {
+        assert_eq!(written(Value::F64(230.4567), Some(2)), "230.46");
+        assert_eq!(written(Value::F64(2.5), Some(3)), "2.5");
+        assert_eq!(written(Value::F64(7.0), Some(2)), "7");
+        assert_eq!(written(Value::F64(-0.0001), Some(2)), "0");
+        assert_eq!(written(Value::U16(42), Some(2)), "42");
+        assert_eq!(written(Value::Bool(true), None), "1");
+    }
+
+    #[test]
+    fn canonical_floats_keep_a_fraction() {
+        let canonical = |value| {
+            let mut out = String::new();
+            write_canonical(&mut out, value).unwrap();
+            out
+        };
+        assert_eq!(canonical(1.0), "1.0");
+        assert_eq!(canonical(0.25), "0.25");
+        assert_eq!(canonical(f64::INFINITY), "+Inf");
+    }
+
+    #[test]
+    fn energy_is_scaled_alongside_or_instead() {
+        assert_eq!(
+            names_and_values(&scale_energy(energy(), Kilowatthours::Off)),
+            [("fems_grid_buy_active_energy_watthours_total", 12500.0), ("fems_grid_power_watts", 230.0)]
+        );
+        assert_eq!(
+            names_and_values(&scale_energy(energy(), Kilowatthours::Alongside)),
+            [
+                ("fems_grid_buy_active_energy_watthours_total", 12500.0),
+                ("fems_grid_buy_active_energy_kilowatthours_total", 12.5),
+                ("fems_grid_power_watts", 230.0),
+            ]
+        );
+
+        let instead = scale_energy(energy(), Kilowatthours::Instead);
+        assert_eq!(
+            names_and_values(&instead),
+            [("fems_grid_buy_active_energy_kilowatthours_total", 12.5), ("fems_grid_power_watts", 230.0)]
+        );
+        assert_eq!(instead[0].labels, [("phase".to_string(), "l1".to_string())]);
+    }
+
+    fn series(labels: &[(&str, &str)]) -> Series {
+        Series {
+            name: "fems_state".to_string(),
+            labels: labels.iter().map(|(l, v)| (l.to_string(), v.to_string())).collect(),
+            value: Value::U16(1),
+        }
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let rendered = render(&[series(&[("site", "say \"hi\"\\\nbye")])], NumberFormat::default());
+        assert_eq!(rendered, "fems_state{site = \"say \\\"hi\\\"\\\\\\nbye\"} 1\n");
+    }
+
+    #[test]
+    fn series_without_labels_have_no_braces() {
+        assert_eq!(render(&[series(&[])], NumberFormat::default()), "fems_state 1\n");
+    }
+}
@@ -0,0 +1,173 @@
+//! Fault and warning bitfields, exported as one `fems_fault` series per bit.
+//!
+//! Which bits a register has depends on the installed hardware, so the registers and their fault
+//! codes are taken from the config.
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use crate::registers::{
+    default_address, deserialize_label_map, Bitfield, Group, MetricDef, MetricKind,
+};
+
+const FAULT_METRIC: &str = "fems_fault";
+
+/// A register whose bits are individual faults, e.g. the warning channels of a battery.
+#[derive(Deserialize)]
+#[serde(try_from = "RawFaultRegister")]
+pub struct FaultRegister {
+    pub component: String,
+    pub address: u16,
+    pub codes: BTreeMap<u8, String>,
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawFaultRegister {
+    /// Component the register belongs to, `address` is relative to its block unless it's `_sum`
+    #[serde(default = "default_component")]
+    component: String,
+    address: u16,
+    /// Fault code of each bit, bits without a code are not exported
+    #[serde(default = "default_codes")]
+    codes: BTreeMap<u8, String>,
+    /// Labels added to the series of this register, to tell registers with the same codes apart
+    #[serde(default, deserialize_with = "deserialize_label_map")]
+    labels: BTreeMap<String, String>,
+}
+
+fn default_component() -> String {
+    "_sum".to_string()
+}
+
+/// Exports all 16 bits as `bit0` to `bit15` if the codes are not known.
+fn default_codes() -> BTreeMap<u8, String> {
+    (0..16).map(|bit| (bit, format!("bit{bit}"))).collect()
+}
+
+impl TryFrom<RawFaultRegister> for FaultRegister {
+    type Error = String;
+
+    fn try_from(raw: RawFaultRegister) -> Result<Self, Self::Error> {
+        let address = raw.address;
+
+        if let Some(base) = default_address(&raw.component).filter(|base| address < *base) {
+            return Err(format!(
+                "fault register {address} is before the start of component {} at {base}",
+                raw.component
+            ));
+        }
+        if let Some(bit) = raw.codes.keys().find(|bit| **bit >= 16) {
+            return Err(format!(
+                "fault register {address} has a code for bit {bit}, registers only have bits 0 to 15"
+            ));
+        }
+        if raw.codes.values().any(|code| code.is_empty()) {
+            return Err(format!("fault register {address} has an empty code"));
+        }
+        if raw.labels.contains_key("code") {
+            return Err(format!("fault register {address} sets the label \"code\" used for its codes"));
+        }
+
+        Ok(FaultRegister {
+            component: raw.component,
+            address,
+            codes: raw.codes,
+            labels: raw.labels,
+        })
+    }
+}
+
+/// Builds one register group per component that has fault registers.
+pub fn fault_groups(faults: &[FaultRegister]) -> Vec<Group> {
+    let mut groups: Vec<Group> = Vec::new();
+
+    for fault in faults {
+        let metric = MetricDef {
+            name: FAULT_METRIC.to_string(),
+            labels: fault
+                .labels
+                .iter()
+                .map(|(l, v)| (l.clone(), v.clone()))
+                .collect(),
+            address: fault.address,
+            modbus_type: Bitfield,
+            kind: MetricKind::Gauge,
+            bits: fault
+                .codes
+                .iter()
+                .map(|(bit, code)| (*bit, code.clone()))
+                .collect(),
+        };
+
+        match groups.iter_mut().find(|g| g.component == fault.component) {
+            Some(group) => group.metrics.push(metric),
+            None => groups.push(Group {
+                name: format!("faults_{}", fault.component),
+                component: fault.component.clone(),
+                metrics: vec![metric],
+                modules: None,
+                devices: None,
+            }),
+        }
+    }
+
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbus::decode_metric;
+
+    fn faults(yaml: &str) -> Result<Vec<FaultRegister>, serde_yaml::Error> {
+        serde_yaml::from_str(yaml)
+    }
+
+    #[test]
+    fn each_bit_with_a_code_is_a_series() {
+        let faults = faults("[{address: 230, codes: {0: overvoltage, 3: overtemperature}, labels: {battery: b1}}]");
+        let groups = fault_groups(&faults.unwrap());
+
+        let mut samples = Vec::new();
+        decode_metric(&groups[0].metrics[0], &[0b1001], &[], &mut samples);
+        let series: Vec<(&str, &str, f64)> = samples
+            .iter()
+            .map(|s| (s.labels[0].1.as_str(), s.labels[1].1.as_str(), s.value.as_f64()))
+            .collect();
+        assert_eq!(series, [("b1", "overvoltage", 1.0), ("b1", "overtemperature", 1.0)]);
+        assert!(samples.iter().all(|s| s.name == FAULT_METRIC));
+    }
+
+    #[test]
+    fn registers_without_codes_export_every_bit() {
+        let groups = fault_groups(&faults("[{address: 230}]").unwrap());
+
+        let mut samples = Vec::new();
+        decode_metric(&groups[0].metrics[0], &[1 << 15], &[], &mut samples);
+        assert_eq!(samples.len(), 16);
+        assert_eq!(samples[15].labels, [("code".to_string(), "bit15".to_string())]);
+        assert_eq!(samples[15].value.as_f64(), 1.0);
+        assert_eq!(samples[0].value.as_f64(), 0.0);
+    }
+
+    #[test]
+    fn registers_are_grouped_by_component() {
+        let faults = faults("[{address: 230}, {component: battery0, address: 10}, {address: 231}]").unwrap();
+        let groups = fault_groups(&faults);
+
+        let names: Vec<(&str, usize)> = groups.iter().map(|g| (g.name.as_str(), g.metrics.len())).collect();
+        assert_eq!(names, [("faults__sum", 2), ("faults_battery0", 1)]);
+    }
+
+    #[test]
+    fn invalid_registers_are_rejected() {
+        // Before the first register of _sum
+        assert!(faults("[{address: 100}]").is_err());
+        assert!(faults("[{address: 230, codes: {16: x}}]").is_err());
+        assert!(faults("[{address: 230, codes: {0: ''}}]").is_err());
+        assert!(faults("[{address: 230, labels: {code: x}}]").is_err());
+    }
+}
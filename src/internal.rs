@@ -0,0 +1,240 @@
+//! Metrics about the exporter itself, served at /exporter/metrics.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::ReadError,
+    exposition::{write_canonical, NumberFormat},
+    modbus::Value,
+};
+
+/// Upper bounds in seconds of the histogram buckets.
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Text format of /exporter/metrics, negotiated with the `Accept` header.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Prometheus,
+    /// OpenMetrics, which is required for exemplars
+    OpenMetrics,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Prometheus => "text/plain; version=0.0.4; charset=utf-8",
+            Format::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        }
+    }
+}
+
+/// Current Unix timestamp in seconds.
+fn unix_time() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+/// Observation linked to the trace it was made in.
+struct Exemplar {
+    trace_id: String,
+    value: f64,
+    /// Unix timestamp in seconds
+    timestamp: f64,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative
+    counts: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+    /// Last traced observation of each bucket, the last one is `+Inf`
+    exemplars: [Option<Exemplar>; BUCKETS.len() + 1],
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64, trace_id: Option<String>) {
+        let bucket = BUCKETS.iter().position(|le| value <= *le);
+        if let Some(bucket) = bucket {
+            self.counts[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+
+        if let Some(trace_id) = trace_id {
+            let timestamp = unix_time();
+            self.exemplars[bucket.unwrap_or(BUCKETS.len())] = Some(Exemplar { trace_id, value, timestamp });
+        }
+    }
+
+    fn render(&self, report: &mut String, name: &str, labels: &str, format: Format) {
+        let mut cumulative = 0;
+        let bounds = BUCKETS.iter().chain([&f64::INFINITY]).map(|le| {
+            let mut bound = String::new();
+            let _ = match format {
+                Format::Prometheus => NumberFormat::default().write(&mut bound, &Value::F64(*le)),
+                Format::OpenMetrics => write_canonical(&mut bound, *le),
+            };
+            bound
+        });
+        let counts = self.counts.iter().copied().chain([self.count - self.counts.iter().sum::<u64>()]);
+        for ((le, count), exemplar) in bounds.zip(counts).zip(&self.exemplars) {
+            cumulative += count;
+            let _ = write!(report, "{name}_bucket{{{labels},le=\"{le}\"}} {cumulative}");
+            if let (Format::OpenMetrics, Some(Exemplar { trace_id, value, timestamp })) = (format, exemplar) {
+                let _ = write!(report, " # {{trace_id=\"{trace_id}\"}} {value} {timestamp}");
+            }
+            report.push('\n');
+        }
+        let _ = writeln!(report, "{name}_sum{{{labels}}} {}", self.sum);
+        let _ = writeln!(report, "{name}_count{{{labels}}} {}", self.count);
+    }
+}
+
+/// Host, error kind and exception code of failed reads.
+type ErrorKey = (SocketAddr, &'static str, Option<u8>);
+
+#[derive(Default)]
+pub struct InternalMetrics {
+    connection_wait: Mutex<BTreeMap<SocketAddr, Histogram>>,
+    scrape_duration: Mutex<BTreeMap<SocketAddr, Histogram>>,
+    read_errors: Mutex<BTreeMap<ErrorKey, u64>>,
+    /// Unix timestamp of the last failed read per host
+    last_error: Mutex<BTreeMap<SocketAddr, f64>>,
+    rejected_scrapes: AtomicU64,
+}
+
+impl InternalMetrics {
+    /// Records how long a scrape waited for the connection to `host` to become available.
+    pub fn observe_connection_wait(&self, host: SocketAddr, wait: Duration) {
+        let mut connection_wait = self.connection_wait.lock().unwrap();
+        connection_wait
+            .entry(host)
+            .or_default()
+            .observe(wait.as_secs_f64(), None);
+    }
+
+    /// Records how long a scrape of /metrics for `host` took, `trace_id` is kept as an exemplar.
+    pub fn observe_scrape(&self, host: SocketAddr, duration: Duration, trace_id: Option<String>) {
+        let mut scrape_duration = self.scrape_duration.lock().unwrap();
+        scrape_duration
+            .entry(host)
+            .or_default()
+            .observe(duration.as_secs_f64(), trace_id);
+    }
+
+    pub fn count_rejected_scrape(&self) {
+        self.rejected_scrapes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_read_error(&self, host: SocketAddr, error: &ReadError) {
+        let code = match error {
+            ReadError::Exception { exception } => Some(exception.code),
+            _ => None,
+        };
+
+        let mut read_errors = self.read_errors.lock().unwrap();
+        *read_errors.entry((host, error.kind(), code)).or_default() += 1;
+
+        self.last_error.lock().unwrap().insert(host, unix_time());
+    }
+
+    pub fn render(&self, format: Format) -> String {
+        let mut report = String::new();
+
+        let name = "fems_exporter_connection_wait_seconds";
+        report.push_str(&format!(
+            "# HELP {name} Time scrapes spent queued for the Modbus connection of a FEMS.\n# TYPE {name} histogram\n"
+        ));
+        for (host, histogram) in self.connection_wait.lock().unwrap().iter() {
+            histogram.render(&mut report, name, &format!("host=\"{host}\""), format);
+        }
+
+        let name = "fems_exporter_scrape_duration_seconds";
+        report.push_str(&format!(
+            "# HELP {name} Time scrapes of /metrics took, including waiting for the connection.\n# TYPE {name} histogram\n"
+        ));
+        for (host, histogram) in self.scrape_duration.lock().unwrap().iter() {
+            histogram.render(&mut report, name, &format!("host=\"{host}\""), format);
+        }
+
+        // OpenMetrics names the counter family without the suffix of its samples
+        let name = "fems_exporter_read_errors_total";
+        let family = match format {
+            Format::Prometheus => name,
+            Format::OpenMetrics => name.trim_end_matches("_total"),
+        };
+        report.push_str(&format!(
+            "# HELP {family} Failed reads of a FEMS by kind of error and Modbus exception code.\n# TYPE {family} counter\n"
+        ));
+        for ((host, kind, code), count) in self.read_errors.lock().unwrap().iter() {
+            let code = code.map(|c| c.to_string()).unwrap_or_default();
+            let _ = writeln!(report, "{name}{{host=\"{host}\",kind=\"{kind}\",code=\"{code}\"}} {count}");
+        }
+
+        let name = "fems_exporter_last_error_timestamp_seconds";
+        report.push_str(&format!(
+            "# HELP {name} Unix time of the last failed read of a FEMS.\n# TYPE {name} gauge\n"
+        ));
+        for (host, timestamp) in self.last_error.lock().unwrap().iter() {
+            let _ = writeln!(report, "{name}{{host=\"{host}\"}} {timestamp}");
+        }
+
+        let name = "fems_exporter_rejected_scrapes_total";
+        let family = match format {
+            Format::Prometheus => name,
+            Format::OpenMetrics => name.trim_end_matches("_total"),
+        };
+        report.push_str(&format!(
+            "# HELP {family} Scrapes answered with 503 because too many were in progress.\n# TYPE {family} counter\n"
+        ));
+        let _ = writeln!(report, "{name} {}", self.rejected_scrapes.load(Ordering::Relaxed));
+
+        if format == Format::OpenMetrics {
+            report.push_str("# EOF\n");
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendered(format: Format) -> String {
+        let mut histogram = Histogram::default();
+        histogram.observe(0.7, None);
+        histogram.observe(20.0, None);
+
+        let mut report = String::new();
+        histogram.render(&mut report, "fems_exporter_scrape_duration_seconds", "host=\"a\"", format);
+        report
+    }
+
+    #[test]
+    fn openmetrics_bounds_are_canonical() {
+        let report = rendered(Format::OpenMetrics);
+        assert!(report.contains("_bucket{host=\"a\",le=\"0.005\"} 0\n"));
+        assert!(report.contains("_bucket{host=\"a\",le=\"1.0\"} 1\n"));
+        assert!(report.contains("_bucket{host=\"a\",le=\"10.0\"} 1\n"));
+        assert!(report.contains("_bucket{host=\"a\",le=\"+Inf\"} 2\n"));
+    }
+
+    #[test]
+    fn prometheus_bounds_are_shortest() {
+        let report = rendered(Format::Prometheus);
+        assert!(report.contains("_bucket{host=\"a\",le=\"1\"} 1\n"));
+        assert!(report.contains("_bucket{host=\"a\",le=\"+Inf\"} 2\n"));
+    }
+}
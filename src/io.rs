@@ -0,0 +1,68 @@
+//! Relays and digital inputs of FEMS IO components, read as coils and discrete inputs.
+//!
+//! Unlike registers, coils and discrete inputs are not part of the OpenEMS component blocks, so
+//! their addresses are absolute.
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use crate::registers::{deserialize_label_map, Group, MetricDef, MetricKind, ModbusType, IO_COMPONENT};
+
+/// A single relay or digital input, exported as 0 or 1.
+#[derive(Deserialize)]
+#[serde(try_from = "RawIoPoint")]
+pub struct IoPoint {
+    metric: MetricDef,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawIoPoint {
+    #[serde(rename = "type")]
+    modbus_type: ModbusType,
+    address: u16,
+    /// Defaults to `fems_relay_state` for coils and `fems_digital_input_state` for discrete inputs
+    name: Option<String>,
+    /// Labels telling the points apart, e.g. `relay: "1"`
+    #[serde(default, deserialize_with = "deserialize_label_map")]
+    labels: BTreeMap<String, String>,
+}
+
+impl TryFrom<RawIoPoint> for IoPoint {
+    type Error = String;
+
+    fn try_from(raw: RawIoPoint) -> Result<Self, Self::Error> {
+        let default_name = match raw.modbus_type {
+            ModbusType::Coil => "fems_relay_state",
+            ModbusType::DiscreteInput => "fems_digital_input_state",
+            _ => return Err(format!("IO point {} must be a coil or discrete_input", raw.address)),
+        };
+
+        Ok(IoPoint {
+            metric: MetricDef {
+                name: raw.name.unwrap_or_else(|| default_name.to_string()),
+                labels: raw.labels.into_iter().collect(),
+                address: raw.address,
+                modbus_type: raw.modbus_type,
+                kind: MetricKind::Gauge,
+                bits: Vec::new(),
+            },
+        })
+    }
+}
+
+/// Builds the `io` group of all configured points, if there are any.
+pub fn io_group(points: &[IoPoint]) -> Option<Group> {
+    if points.is_empty() {
+        return None;
+    }
+
+    Some(Group {
+        name: "io".to_string(),
+        component: IO_COMPONENT.to_string(),
+        metrics: points.iter().map(|p| p.metric.clone()).collect(),
+        modules: None,
+        devices: None,
+    })
+}
@@ -0,0 +1,118 @@
+//! Client of the OpenEMS JSON-RPC API, as served by the REST controller at `/jsonrpc`.
+//!
+//! Some channels, like predictions, are not available via Modbus at all.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{header, Method, Request, Uri};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{credentials::Credentials, error::ReadError};
+
+/// Port of the OpenEMS REST controller, used if a target has no `jsonrpc_url`.
+const REST_PORT: u16 = 80;
+
+pub struct JsonRpcClient {
+    uri: Uri,
+    /// Authorization header, kept out of logs like the credentials it was built from
+    authorization: Option<String>,
+    client: Client<HttpConnector, Full<Bytes>>,
+    next_id: AtomicU64,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    result: Option<Value>,
+    error: Option<RpcError>,
+}
+
+#[derive(Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl JsonRpcClient {
+    /// Client for the API at `url`, or at the default URL of the FEMS at `host`.
+    pub fn new(url: Option<&str>, host: IpAddr, credentials: Option<&Credentials>) -> Result<Self, String> {
+        let url = match url {
+            Some(url) => url.to_string(),
+            None => format!("http://{}/jsonrpc", SocketAddr::new(host, REST_PORT)),
+        };
+        let uri: Uri = url.parse().map_err(|e| format!("invalid JSON-RPC URL {url}: {e}"))?;
+        if uri.scheme_str() != Some("http") {
+            return Err(format!("invalid JSON-RPC URL {url}: only http:// is supported"));
+        }
+
+        Ok(JsonRpcClient {
+            uri,
+            authorization: credentials.map(Credentials::authorization),
+            client: Client::builder(TokioExecutor::new()).build_http(),
+            next_id: AtomicU64::new(1),
+        })
+    }
+
+    /// Sends `method` to the edge and returns its result.
+    pub async fn request(&self, method: &str, params: Value) -> Result<Value, ReadError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed).to_string();
+        let body = json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params});
+
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(&self.uri)
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(authorization) = &self.authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        let request = request
+            .body(Full::from(body.to_string()))
+            .map_err(|e| ReadError::JsonRpc { message: e.to_string() })?;
+
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|e| ReadError::JsonRpc { message: format!("unable to reach {}: {e}", self.uri) })?;
+        let status = response.status();
+        let content = response
+            .into_body()
+            .collect()
+            .await
+            .map_err(|e| ReadError::JsonRpc { message: e.to_string() })?
+            .to_bytes();
+        if !status.is_success() {
+            return Err(ReadError::JsonRpc { message: format!("{} responded with {status}", self.uri) });
+        }
+
+        let response: Response = serde_json::from_slice(&content)
+            .map_err(|e| ReadError::Decode { message: format!("invalid JSON-RPC response: {e}") })?;
+        match response {
+            Response { error: Some(RpcError { code, message }), .. } => {
+                Err(ReadError::JsonRpc { message: format!("{method} failed with code {code}: {message}") })
+            }
+            Response { result: Some(result), .. } => Ok(result),
+            Response { result: None, .. } => {
+                Err(ReadError::Decode { message: "JSON-RPC response without result".to_string() })
+            }
+        }
+    }
+
+    /// Sends `method` to the component `component_id`, wrapped in a `componentJsonApi` request.
+    pub async fn component_request(&self, component_id: &str, method: &str, params: Value) -> Result<Value, ReadError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed).to_string();
+        let payload = json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params});
+        let params = json!({"componentId": component_id, "payload": payload});
+
+        self.request("componentJsonApi", params).await
+    }
+}
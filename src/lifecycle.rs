@@ -0,0 +1,261 @@
+//! Startup and shutdown of the exporter, with exit codes supervisors can tell failures apart by.
+//!
+//! `/-/healthy`, `/-/ready` and `/-/quit` behave like those of Prometheus, so the usual tooling
+//! works with the exporter; `/-/quit` has to be enabled with `--enable-lifecycle` and is only
+//! allowed for admin tenants, so it isn't available without one.
+//!
+//! Exit codes follow `sysexits.h`: 78 for an invalid config or command line, 69 if a listening
+//! socket can't be opened, e.g. because the port is in use, and 70 for failures while running,
+//! including panics. `check-config` keeps exiting with 1 for invalid configs.
+
+use std::{
+    error::Error,
+    fmt, io, panic, process,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Router,
+};
+use tokio::sync::Notify;
+use tracing::{error, info};
+
+use crate::{config::Config, modbus::ModbusState};
+
+/// `EX_CONFIG`
+pub const EXIT_CONFIG: u8 = 78;
+/// `EX_UNAVAILABLE`
+pub const EXIT_BIND: u8 = 69;
+/// `EX_SOFTWARE`
+pub const EXIT_RUNTIME: u8 = 70;
+
+/// `check-config` found problems, they are printed already
+pub const EXIT_CHECK: u8 = 1;
+
+/// Why the exporter stopped before being asked to.
+pub enum Failure {
+    /// `check-config` found problems in the config file
+    Check,
+    /// The config file or the command line options are invalid
+    Config(Box<dyn Error>),
+    /// A listening socket couldn't be opened
+    Bind(io::Error),
+    /// Anything failing once the config was accepted
+    Runtime(Box<dyn Error>),
+}
+
+impl Failure {
+    pub fn config(error: impl Into<Box<dyn Error>>) -> Self {
+        Failure::Config(error.into())
+    }
+
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Failure::Check => EXIT_CHECK,
+            Failure::Config(_) => EXIT_CONFIG,
+            Failure::Bind(_) => EXIT_BIND,
+            Failure::Runtime(_) => EXIT_RUNTIME,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Check => f.write_str("the config file has problems"),
+            Failure::Config(e) => write!(f, "invalid configuration: {e}"),
+            Failure::Bind(e) => write!(f, "unable to listen: {e}"),
+            Failure::Runtime(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<Box<dyn Error>> for Failure {
+    fn from(error: Box<dyn Error>) -> Self {
+        Failure::Runtime(error)
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(error: io::Error) -> Self {
+        Failure::Runtime(error.into())
+    }
+}
+
+/// Exits with [`EXIT_RUNTIME`] on any panic, also of spawned tasks.
+///
+/// A panicked task leaves the state it shared behind in an unknown condition, so rather than
+/// continuing with e.g. a poisoned lock, the exporter stops and leaves restarting to its supervisor.
+pub fn exit_on_panic() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        error!("panicked, exiting: {info}");
+        process::exit(EXIT_RUNTIME.into());
+    }));
+}
+
+/// Logs the version and the effective configuration, with `settings` from the command line.
+///
+/// Credentials and tokens are never logged, only how targets authenticate.
+pub fn banner(config: &Config, settings: &[(&str, String)]) {
+    info!("fems_exporter {} starting", env!("CARGO_PKG_VERSION"));
+    for (name, value) in settings {
+        info!("{name}: {value}");
+    }
+
+    for target in &config.targets {
+        info!(
+            host = %target.host,
+            unit_id = target.unit_id,
+            fems_id = target.fems_id,
+            backend = %target.backend,
+            auth = target.auth.as_ref().map(|a| a.method()),
+            jsonrpc_url = target.jsonrpc_url.as_deref().map(redact_url),
+            "target"
+        );
+    }
+    if let Some(otlp) = &config.otlp {
+        info!(endpoint = redact_url(&otlp.endpoint), interval = otlp.interval, tracing = otlp.tracing, "OTLP export");
+    }
+    for tenant in &config.tenants {
+        info!(name = tenant.name, admin = tenant.admin, "tenant");
+    }
+}
+
+/// `url` without the password of its user info, if it has one.
+pub fn redact_url(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_string();
+    };
+    let authority_end = rest.find('/').unwrap_or(rest.len());
+    let Some(at) = rest[..authority_end].rfind('@') else {
+        return url.to_string();
+    };
+
+    let user = rest[..at].split(':').next().unwrap_or_default();
+    format!("{scheme}://{user}:<redacted>{}", &rest[at..])
+}
+
+/// Shutdown requested via `/-/quit`, a signal or the Windows service manager, shared by the
+/// handlers and the servers.
+#[derive(Default)]
+pub struct Shutdown {
+    quit: Notify,
+    stopping: AtomicBool,
+}
+
+impl Shutdown {
+    /// Asks the exporter to shut down gracefully.
+    pub fn request(&self) {
+        self.quit.notify_one();
+    }
+
+    /// Completes once a shutdown was requested, also if that happened before.
+    pub async fn quit_requested(&self) {
+        self.quit.notified().await;
+    }
+
+    /// Marks the exporter as shutting down, so it reports not being ready anymore.
+    pub fn stopping(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+}
+
+pub fn router() -> Router<ModbusState> {
+    Router::new()
+        .route("/-/healthy", get(healthy).head(healthy))
+        .route("/-/ready", get(ready).head(ready))
+        .route("/-/quit", post(quit).put(quit))
+}
+
+async fn healthy() -> &'static str {
+    "fems_exporter is Healthy.\n"
+}
+
+/// Ready as soon as it serves HTTP, everything else is set up before; not anymore once stopping.
+async fn ready(State(state): State<ModbusState>) -> Response {
+    if state.shutdown.stopping.load(Ordering::SeqCst) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "fems_exporter is shutting down.\n").into_response();
+    }
+    "fems_exporter is Ready.\n".into_response()
+}
+
+/// Starts the graceful shutdown, only for admins and with `--enable-lifecycle`.
+async fn quit(headers: HeaderMap, State(state): State<ModbusState>) -> Response {
+    // Without tenants every request would be allowed, startup refuses that already
+    if !state.lifecycle_api || !state.tenants.has_admin() {
+        return (StatusCode::FORBIDDEN, "Lifecycle API is not enabled.\n").into_response();
+    }
+    let scope = state.tenants.authorize(&headers);
+    if let Err(denied) = scope.and_then(|scope| state.tenants.check_admin(&scope)) {
+        return denied.into_response();
+    }
+
+    info!("shutdown requested via /-/quit");
+    state.shutdown.request();
+    "Requesting termination... Goodbye!\n".into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::http::header;
+    use futures::{poll, FutureExt};
+
+    use super::*;
+    use crate::tenants::{TenantConfig, Tenants};
+
+    fn state(lifecycle_api: bool, tenants: &str) -> ModbusState {
+        let tenants: Vec<TenantConfig> = serde_yaml::from_str(tenants).unwrap();
+        ModbusState {
+            lifecycle_api,
+            tenants: Arc::new(Tenants::new(&tenants, &[])),
+            ..Default::default()
+        }
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, format!("Bearer {token}").parse().unwrap());
+        headers
+    }
+
+    async fn requested(state: &ModbusState) -> bool {
+        poll!(state.shutdown.quit_requested().boxed()).is_ready()
+    }
+
+    const TENANTS: &str = "[{name: ops, token: secret, admin: true}, {name: acme, token: acme}]";
+
+    #[tokio::test]
+    async fn quit_is_disabled_by_default() {
+        let state = state(false, TENANTS);
+        let response = quit(bearer("secret"), State(state.clone())).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(!requested(&state).await);
+    }
+
+    #[tokio::test]
+    async fn quit_needs_tenants() {
+        let state = state(true, "[]");
+        let response = quit(HeaderMap::new(), State(state.clone())).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(!requested(&state).await);
+    }
+
+    #[tokio::test]
+    async fn quit_needs_an_admin_token() {
+        let state = state(true, TENANTS);
+        assert_eq!(quit(HeaderMap::new(), State(state.clone())).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(quit(bearer("acme"), State(state.clone())).await.status(), StatusCode::FORBIDDEN);
+        assert!(!requested(&state).await);
+
+        assert_eq!(quit(bearer("secret"), State(state.clone())).await.status(), StatusCode::OK);
+        assert!(requested(&state).await);
+    }
+}
@@ -0,0 +1,148 @@
+//! Limit of concurrently handled scrapes, protecting small hosts from bursts of scrapes.
+//!
+//! Scrapes beyond the limit wait for a free slot, as long as not too many are waiting already.
+//! Any further scrape is answered with 503 and `Retry-After`.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+use crate::internal::InternalMetrics;
+
+/// Seconds a rejected scraper is asked to wait, scrapes usually take less than that.
+const RETRY_AFTER: &str = "1";
+
+#[derive(Clone)]
+pub struct ScrapeLimit {
+    slots: Arc<Semaphore>,
+    queued: Arc<AtomicUsize>,
+    queue_depth: usize,
+    internal: Arc<InternalMetrics>,
+}
+
+impl ScrapeLimit {
+    pub fn new(concurrency: usize, queue_depth: usize, internal: Arc<InternalMetrics>) -> Self {
+        ScrapeLimit {
+            slots: Arc::new(Semaphore::new(concurrency)),
+            queued: Default::default(),
+            queue_depth,
+            internal,
+        }
+    }
+}
+
+impl ScrapeLimit {
+    /// Waits for a free slot, `None` if too many scrapes are waiting already.
+    async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        if let Ok(permit) = self.slots.clone().try_acquire_owned() {
+            return Some(permit);
+        }
+
+        // Leaves the queue also if the scrape is cancelled while waiting, e.g. on a scrape timeout
+        let queued = Queued::enter(&self.queued);
+        if queued.ahead >= self.queue_depth {
+            return None;
+        }
+        // The semaphore is never closed
+        Some(self.slots.clone().acquire_owned().await.expect("scrape limit closed"))
+    }
+}
+
+/// Place of a scrape in the queue, left when dropped.
+struct Queued<'a> {
+    queued: &'a AtomicUsize,
+    /// Scrapes that were waiting already
+    ahead: usize,
+}
+
+impl<'a> Queued<'a> {
+    fn enter(queued: &'a AtomicUsize) -> Self {
+        let ahead = queued.fetch_add(1, Ordering::SeqCst);
+        Queued { queued, ahead }
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Middleware running the request once a slot is free, or rejecting it if the queue is full.
+pub async fn limit(State(limit): State<ScrapeLimit>, request: Request, next: Next) -> Response {
+    let Some(permit) = limit.acquire().await else {
+        limit.internal.count_rejected_scrape();
+        warn!(path = request.uri().path(), "too many concurrent scrapes, rejecting");
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, RETRY_AFTER)],
+            "too many concurrent scrapes",
+        )
+            .into_response();
+    };
+
+    let response = next.run(request).await;
+    drop(permit);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{poll, FutureExt};
+
+    use super::*;
+
+    fn limit(concurrency: usize, queue_depth: usize) -> ScrapeLimit {
+        ScrapeLimit::new(concurrency, queue_depth, Default::default())
+    }
+
+    #[tokio::test]
+    async fn scrapes_wait_for_a_free_slot() {
+        let limit = limit(1, 1);
+        let running = limit.acquire().await.unwrap();
+
+        let mut waiting = limit.acquire().boxed();
+        assert!(poll!(&mut waiting).is_pending());
+        assert_eq!(limit.queued.load(Ordering::SeqCst), 1);
+
+        drop(running);
+        assert!(waiting.await.is_some());
+        assert_eq!(limit.queued.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn full_queues_reject_scrapes() {
+        let limit = limit(1, 1);
+        let _running = limit.acquire().await.unwrap();
+        let mut waiting = limit.acquire().boxed();
+        assert!(poll!(&mut waiting).is_pending());
+
+        assert!(limit.acquire().await.is_none());
+        assert_eq!(limit.queued.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn cancelled_scrapes_leave_the_queue() {
+        let limit = limit(1, 1);
+        let _running = limit.acquire().await.unwrap();
+
+        for _ in 0..3 {
+            let mut waiting = limit.acquire().boxed();
+            assert!(poll!(&mut waiting).is_pending());
+            drop(waiting);
+            assert_eq!(limit.queued.load(Ordering::SeqCst), 0);
+        }
+        let mut waiting = limit.acquire().boxed();
+        assert!(poll!(&mut waiting).is_pending());
+    }
+}
@@ -0,0 +1,60 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener},
+};
+
+use socket2::{Domain, Socket, Type};
+
+/// Opens a listening socket for every address in `bind`.
+///
+/// The IPv6 unspecified address `::` also accepts IPv4 connections, unless `0.0.0.0` is bound
+/// separately.
+pub fn bind(bind: &[IpAddr], port: u16) -> io::Result<Vec<TcpListener>> {
+    let ipv4_unspecified = bind.contains(&IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+
+    bind.iter()
+        .map(|ip| {
+            let address = SocketAddr::new(*ip, port);
+            let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+
+            if ip.is_ipv6() {
+                // Dual-stack behavior of the OS is not consistent, request it explicitly
+                socket.set_only_v6(!ip.is_unspecified() || ipv4_unspecified)?;
+            }
+            socket.set_reuse_address(true)?;
+            socket.set_nonblocking(true)?;
+            socket.bind(&address.into()).map_err(|e| {
+                io::Error::new(e.kind(), format!("unable to bind to {address}: {e}"))
+            })?;
+            socket.listen(1024)?;
+
+            Ok(socket.into())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv6Addr, TcpStream};
+
+    use super::*;
+
+    #[test]
+    fn unspecified_ipv6_accepts_ipv4() {
+        let listeners = bind(&[IpAddr::V6(Ipv6Addr::UNSPECIFIED)], 0).unwrap();
+        let port = listeners[0].local_addr().unwrap().port();
+
+        TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        TcpStream::connect((Ipv6Addr::LOCALHOST, port)).unwrap();
+    }
+
+    #[test]
+    fn unspecified_ipv4_and_ipv6_share_the_port() {
+        // A free port, both listeners need the same one
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port();
+        let listeners = bind(&[IpAddr::V6(Ipv6Addr::UNSPECIFIED), IpAddr::V4(Ipv4Addr::UNSPECIFIED)], port).unwrap();
+
+        assert_eq!(listeners.len(), 2);
+        TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+    }
+}
@@ -0,0 +1,113 @@
+//! Lines and columns of the entries of a YAML document, for problems found after parsing.
+//!
+//! serde_yaml only knows where parsing failed, so the document is scanned once more for the
+//! position of every node, keyed by its path like `targets[0].optional_groups`.
+
+use std::collections::HashMap;
+
+use yaml_rust2::{
+    parser::{Event, MarkedEventReceiver, Parser},
+    scanner::Marker,
+};
+
+/// Line and column of every node, both starting at 1 like in the errors of serde_yaml.
+#[derive(Default)]
+pub struct Locations(HashMap<String, (usize, usize)>);
+
+impl Locations {
+    /// Locates the nodes of `yaml`, none if it can't be parsed.
+    pub fn scan(yaml: &str) -> Self {
+        let mut scanner = Scanner::default();
+        match Parser::new_from_str(yaml).load(&mut scanner, false) {
+            Ok(()) => Locations(scanner.locations),
+            Err(_) => Locations::default(),
+        }
+    }
+
+    /// ` at line L column C` for the node at `path`, empty if it's not known.
+    pub fn at(&self, path: &str) -> String {
+        match self.0.get(path) {
+            Some((line, column)) => format!(" at line {line} column {column}"),
+            None => String::new(),
+        }
+    }
+}
+
+/// Collection the scanner is in.
+enum Frame {
+    Mapping { path: String, key: Option<String> },
+    Sequence { path: String, index: usize },
+}
+
+#[derive(Default)]
+struct Scanner {
+    frames: Vec<Frame>,
+    locations: HashMap<String, (usize, usize)>,
+}
+
+impl Scanner {
+    /// Path of the node starting at `position`, `None` for the keys of mappings.
+    fn enter(&mut self, event: &Event, position: (usize, usize)) -> Option<String> {
+        match self.frames.last_mut() {
+            None => Some(String::new()),
+            Some(Frame::Sequence { path, index }) => {
+                *index += 1;
+                Some(format!("{path}[{}]", *index - 1))
+            }
+            Some(Frame::Mapping { path, key }) => match key.take() {
+                Some(key) if path.is_empty() => Some(key),
+                Some(key) => Some(format!("{path}.{key}")),
+                None => {
+                    // Block mappings start at their first key, the parser marks them at its colon
+                    self.locations.entry(path.clone()).or_insert(position);
+                    // Keys that aren't scalars can't be part of a path
+                    *key = Some(match event {
+                        Event::Scalar(value, ..) => value.clone(),
+                        _ => "?".to_string(),
+                    });
+                    None
+                }
+            },
+        }
+    }
+}
+
+impl MarkedEventReceiver for Scanner {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        if matches!(event, Event::SequenceEnd | Event::MappingEnd) {
+            self.frames.pop();
+            return;
+        }
+        if !matches!(event, Event::Scalar(..) | Event::Alias(..) | Event::SequenceStart(..) | Event::MappingStart(..)) {
+            return;
+        }
+
+        let position = (mark.line(), mark.col() + 1);
+        let path = self.enter(&event, position);
+        if let Some(path) = path.as_ref().filter(|_| !matches!(event, Event::MappingStart(..))) {
+            self.locations.entry(path.clone()).or_insert(position);
+        }
+
+        let path = path.unwrap_or_default();
+        match event {
+            Event::SequenceStart(..) => self.frames.push(Frame::Sequence { path, index: 0 }),
+            Event::MappingStart(..) => self.frames.push(Frame::Mapping { path, key: None }),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_located_by_path() {
+        let locations = Locations::scan("targets:\n  - host: a\n    groups: [x, y]\notlp:\n  interval: 0\n");
+
+        assert_eq!(locations.at("targets[0]"), " at line 2 column 5");
+        assert_eq!(locations.at("targets[0].groups[1]"), " at line 3 column 17");
+        assert_eq!(locations.at("otlp.interval"), " at line 5 column 13");
+        assert_eq!(locations.at("targets[1]"), "");
+    }
+}
@@ -1,196 +1,495 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
     net::{SocketAddr, IpAddr, Ipv4Addr},
-    sync::Arc, error::Error,
+    sync::{Arc, RwLock}, path::{Path, PathBuf}, process::ExitCode, time::{Duration, Instant},
 };
 
 use axum::{
-    extract::{Query, State},
-    http::StatusCode,
+    extract::{rejection::QueryRejection, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
     routing::get,
     Router,
 };
-use clap::Parser;
-use tokio::{sync::Mutex, signal};
-use tokio_modbus::{client::Context, prelude::*};
+use clap::{Parser, Subcommand};
+use futures::{future, FutureExt};
+use tokio::signal;
+use tracing::{error, info};
 
 use serde::Deserialize;
 
-enum ModbusType {
-    U16,
-    F32,
-    F64,
-}
+mod api;
+mod backend;
+mod config;
+mod credentials;
+mod custom;
+mod dashboard;
+mod downsample;
+mod error;
+mod exposition;
+mod faults;
+mod internal;
+mod io;
+mod jsonrpc;
+mod lifecycle;
+mod limit;
+mod listener;
+mod locate;
+mod modbus;
+mod modules;
+mod nature;
+mod otlp;
+mod persist;
+mod poller;
+mod predictions;
+mod push;
+mod registers;
+mod relabel;
+mod server;
+#[cfg(windows)]
+mod service;
+mod simulate;
+mod stream;
+mod targets;
+mod tenants;
+
+use backend::Backends;
+use config::Config;
+use internal::Format;
+use jsonrpc::JsonRpcClient;
+use lifecycle::{Failure, Shutdown};
+use limit::ScrapeLimit;
+use modbus::{default_unit_id, deserialize_host, read_samples, AdaptiveTtl, Device, ModbusState};
+use otlp::ScrapeSpan;
+use predictions::{Predictor, Predictors};
+use server::Timeouts;
+use tenants::Tenants;
 
-use ModbusType::{U16, F32, F64};
+#[derive(Deserialize)]
+struct Params {
+    #[serde(deserialize_with = "deserialize_host")]
+    host: SocketAddr,
+    #[serde(default = "default_unit_id")]
+    unit_id: u8,
+    /// Defaults to the `fems_id` of the configured target, see [`ModbusState::default_fems_id`]
+    fems_id: Option<String>,
+    /// Also export the undecoded registers, to debug decoding
+    #[serde(default)]
+    debug: bool,
+}
 
-impl ModbusType {
-    fn register_count(&self) -> u16 {
-        match self {
-            U16 => 1,
-            F32 => 2,
-            F64 => 4,
+async fn metrics(
+    headers: HeaderMap,
+    query: Result<Query<Params>, QueryRejection>,
+    State(state): State<ModbusState>,
+) -> Response {
+    let Params { host, unit_id, fems_id, debug } = match query {
+        Ok(Query(params)) => params,
+        Err(rejection) => {
+            let message = format!(
+                "{}\nexpected ?host=<address>[:<port>], optionally with unit_id=<id> and fems_id=<id>\n",
+                rejection.body_text()
+            );
+            return (StatusCode::BAD_REQUEST, message).into_response();
         }
+    };
+    let device = Device { host, unit_id };
+    let fems_id = fems_id.unwrap_or_else(|| state.default_fems_id(device));
+    let scope = match state.tenants.authorize(&headers) {
+        Ok(scope) => scope,
+        Err(denied) => return denied.into_response(),
+    };
+    if let Err(denied) = state.tenants.check(&scope, device, &fems_id) {
+        return denied.into_response();
+    }
+
+    let started_at = Instant::now();
+    let span = state.tracer.as_ref().map(|tracer| ScrapeSpan::start(tracer, &headers, &fems_id));
+    let result = read_samples(&state, device).await;
+    let trace_id = span.as_ref().map(ScrapeSpan::trace_id);
+    state.internal.observe_scrape(host, started_at.elapsed(), trace_id);
+    if let Some(span) = span {
+        span.end(result.as_ref().err().map(|e| e.kind()));
     }
+
+    let mut samples = match result {
+        Ok(samples) => samples,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+    samples.extend(state.scrape_samples(device));
+    samples.extend(downsample::drain(&state.windows, device));
+    if debug {
+        samples.extend(state.debug_samples(device));
+    }
+
+    let body = exposition::stream(state.series(&samples, &fems_id), state.number_format);
+    ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], body).into_response()
 }
 
-fn decode_u16(data: &[u16]) -> u16 {
-    *data.first().unwrap()
+async fn internal_metrics(headers: HeaderMap, State(state): State<ModbusState>) -> Response {
+    let scope = state.tenants.authorize(&headers);
+    if let Err(denied) = scope.and_then(|scope| state.tenants.check_admin(&scope)) {
+        return denied.into_response();
+    }
+
+    let openmetrics = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/openmetrics-text"));
+    let format = if openmetrics { Format::OpenMetrics } else { Format::Prometheus };
+
+    ([(header::CONTENT_TYPE, format.content_type())], state.internal.render(format)).into_response()
 }
 
-fn decode_f32(data: &[u16]) -> f32 {
-    let bytes: [u8; 4] = data
-        .iter()
-        .flat_map(|word| word.to_be_bytes())
-        .collect::<Vec<u8>>()
-        .try_into()
-        .unwrap();
-    f32::from_be_bytes(bytes)
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(short, long, default_value_t = 80)]
+    port: u16,
+    /// Address to listen on, may be given multiple times; `::` listens on IPv6 and IPv4
+    #[arg(short, long, default_values_t = [IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))])]
+    bind: Vec<IpAddr>,
+    /// Usable battery capacity in Wh, overrides the capacity reported by FEMS
+    #[arg(long)]
+    battery_capacity: Option<f64>,
+    /// Default interval in seconds between updates pushed to /stream clients
+    #[arg(long, default_value_t = 5)]
+    stream_interval: u64,
+    /// Seconds during which read values are reused for further scrapes of the same device, 0 disables caching
+    #[arg(long, default_value_t = 5)]
+    cache_ttl: u64,
+    /// Seconds a read may take before the cache TTL of the device is doubled, up to `--max-cache-ttl`;
+    /// halved again once reads take less than half of it. Unset keeps the TTL fixed
+    #[arg(long)]
+    slow_read_threshold: Option<f64>,
+    /// Highest cache TTL in seconds slow devices are given
+    #[arg(long, default_value_t = 60)]
+    max_cache_ttl: u64,
+    /// Seconds a read of a device may take, including connecting, before its connection is dropped
+    #[arg(long, default_value_t = 10)]
+    modbus_timeout: u64,
+    /// Times a read is repeated right away after a timeout or a lost connection; other errors are not retried
+    #[arg(long, default_value_t = 1)]
+    modbus_retries: u32,
+    /// Failed reads kept per target and listed at /targets
+    #[arg(long, default_value_t = 10)]
+    error_history: usize,
+    /// Read metrics at adjacent addresses together, in reads of up to this many registers;
+    /// lowered per device if it rejects long reads
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..=125))]
+    read_window: Option<u16>,
+    /// Scrapes of /metrics handled at the same time, further ones wait in a queue; unlimited if unset
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_concurrent_scrapes: Option<usize>,
+    /// Scrapes that may wait for one of the `--max-concurrent-scrapes`, more are answered with 503
+    #[arg(long, default_value_t = 16)]
+    scrape_queue_depth: usize,
+    /// Seconds an HTTP client may take to send its request headers or to answer an HTTP/2 ping
+    #[arg(long, default_value_t = 30)]
+    http_timeout: u64,
+    /// Connect to the configured targets at startup instead of on their first scrape
+    #[arg(long)]
+    warm_up: bool,
+    /// File to keep the last read values in across restarts, they are served as stale until the first successful read
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+    /// YAML file with statically configured targets and exporters
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Pushgateway to push the metrics of the configured targets to, e.g. http://pushgateway:9091
+    #[arg(long)]
+    pushgateway_url: Option<String>,
+    /// Seconds between two pushes to the Pushgateway
+    #[arg(long, default_value_t = 30)]
+    pushgateway_interval: u64,
+    /// Don't serve HTTP, e.g. when only pushing to a Pushgateway
+    #[arg(long)]
+    no_listen: bool,
+    /// Serve synthetic values following a daily PV curve instead of reading any FEMS, e.g. for demos
+    #[arg(long)]
+    simulate: bool,
+    /// Allow admins to shut the exporter down via /-/quit, needs an admin tenant in the config
+    #[arg(long)]
+    enable_lifecycle: bool,
+    /// Load and check the config and open the listening sockets, then exit without serving;
+    /// exits with 78 for an invalid config and 69 if a socket can't be opened
+    #[arg(long)]
+    validate_and_exit: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn decode_f64(data: &[u16]) -> f64 {
-    let bytes: [u8; 8] = data
-        .iter()
-        .flat_map(|word| word.to_be_bytes())
-        .collect::<Vec<u8>>()
-        .try_into()
-        .unwrap();
-    f64::from_be_bytes(bytes)
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Validate a config file without starting the exporter, exits with 1 if it has problems
+    CheckConfig {
+        #[arg(short, long)]
+        config: PathBuf,
+    },
+    /// Print a Grafana dashboard for the metrics exported with the given config
+    Dashboard {
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+        /// Title of the dashboard
+        #[arg(long, default_value = "FEMS")]
+        title: String,
+    },
+    /// Run as a Windows service, or install or remove it
+    #[cfg(windows)]
+    Service {
+        #[command(subcommand)]
+        action: service::Action,
+    },
 }
 
-const MODBUS_METRICS: [(&str, &[(&str, &str)], u16, ModbusType); 32] = [
-    ("fems_state", &[], 222, U16),
-    ("fems_grid_mode", &[], 417, U16),
-    ("fems_ess_soc_percent", &[], 302, U16),
-    ("fems_ess_power_watts_total", &[], 303, F32),
-    ("fems_ess_power_watts", &[("phase", "l1")], 391, F32),
-    ("fems_ess_power_watts", &[("phase", "l2")], 393, F32),
-    ("fems_ess_power_watts", &[("phase", "l3")], 395, F32),
-    ("fems_ess_discharge_power_watts_total", &[], 415, F32),
-    ("fems_ess_reactive_power_voltampere", &[], 309, F32),
-    ("fems_grid_power_watts_total", &[], 315, F32),
-    ("fems_grid_power_watts", &[("phase", "l1")], 397, F32),
-    ("fems_grid_power_watts", &[("phase", "l2")], 399, F32),
-    ("fems_grid_power_watts", &[("phase", "l3")], 401, F32),
-    ("fems_production_power_watts_total", &[], 327, F32),
-    ("fems_production_power_watts", &[("type", "dc")], 339, F32),
-    ("fems_production_power_watts", &[("type", "ac"), ("phase", "l1")], 403, F32),
-    ("fems_production_power_watts", &[("type", "ac"), ("phase", "l2")], 405, F32),
-    ("fems_production_power_watts", &[("type", "ac"), ("phase", "l3")], 407, F32),
-    ("fems_consumption_power_watts_total", &[], 343, F32),
-    ("fems_consumption_power_watts", &[("phase", "l3")], 409, F32),
-    ("fems_consumption_power_watts", &[("phase", "l3")], 411, F32),
-    ("fems_consumption_power_watts", &[("phase", "l3")], 413, F32),
-    ("fems_ess_charge_energy_watthours", &[], 351, F64),
-    ("fems_ess_discharge_energy_watthours", &[], 355, F64),
-    ("fems_ess_dc_charge_energy_watthours", &[], 383, F64),
-    ("fems_ess_dc_discharge_energy_watthours", &[], 387, F64),
-    ("fems_grid_buy_energy_watthours", &[], 359, F64),
-    ("fems_grid_sell_energy_watthours", &[], 363, F64),
-    ("fems_production_energy_watthours_total", &[], 367, F64),
-    ("fems_production_energy_watthours", &[("type", "ac")], 371, F64),
-    ("fems_production_energy_watthours", &[("type", "dc")], 375, F64),
-    ("fems_consumption_energy_watthours", &[], 379, F64),
-];
+/// Prints all problems of the config at `path` and returns whether there were none.
+fn check_config(path: &Path) -> bool {
+    let config = match Config::load(path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            return false;
+        }
+    };
 
-#[derive(Deserialize)]
-struct Params {
-    host: SocketAddr,
-    fems_id: String,
+    let problems = config.check();
+    for problem in &problems {
+        eprintln!("invalid config file {}: {problem}", path.display());
+    }
+    if problems.is_empty() {
+        println!("config file {} is valid", path.display());
+    }
+
+    problems.is_empty()
 }
 
-#[allow(unused_variables)]
-async fn metrics(
-    Query(Params { host, fems_id }): Query<Params>,
-    State(state): State<ModbusState>,
-) -> (StatusCode, String) {
-    // Get existing connection or open a new one
-    let mut contexts = state.0.lock().await;
-    let ctx = match contexts.entry(host) {
-        Entry::Occupied(e) => e.into_mut(),
-        Entry::Vacant(e) => {
-            let mut ctx = match tcp::connect(host).await {
-                Ok(ctx) => ctx,
-                Err(e) => {
-                    return (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        format!("unable to connect to fems modbus at {host}: {e}"),
-                    )
-                }
-            };
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    #[cfg(windows)]
+    if let Some(Command::Service { action }) = &args.command {
+        return service::main(action);
+    }
+
+    tracing_subscriber::fmt::init();
+    lifecycle::exit_on_panic();
+
+    ExitCode::from(exit_code(runtime().block_on(run(args, Default::default()))))
+}
+
+/// Multi-threaded runtime like the one of `#[tokio::main]`.
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("failed to start the Tokio runtime")
+}
 
-            ctx.set_slave(Slave(1));
-            e.insert(ctx)
+/// Exit code for how `run` ended, logging the failure if it failed.
+fn exit_code(result: Result<(), Failure>) -> u8 {
+    match result {
+        Ok(()) => 0,
+        Err(failure) => {
+            error!("{failure}");
+            failure.exit_code()
         }
+    }
+}
+
+/// Runs the exporter until a shutdown is requested via `shutdown` or a signal.
+async fn run(args: Args, shutdown: Arc<Shutdown>) -> Result<(), Failure> {
+    match &args.command {
+        Some(Command::CheckConfig { config }) => {
+            return match check_config(config) {
+                true => Ok(()),
+                false => Err(Failure::Check),
+            };
+        }
+        Some(Command::Dashboard { config, title }) => {
+            let config = match config {
+                Some(path) => Config::load(path).map_err(Failure::Config)?,
+                None => Config::default(),
+            };
+            println!("{:#}", dashboard::render(&config, title));
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let config = match &args.config {
+        Some(path) => Config::load(path).map_err(Failure::Config)?,
+        None => Config::default(),
+    };
+    let problems = config.check();
+    if !problems.is_empty() {
+        return Err(Failure::config(problems.join("; ")));
+    }
+    let pushgateway = match &args.pushgateway_url {
+        Some(url) => Some(push::check(url, &config.targets).map_err(Failure::config)?),
+        None => None,
     };
+    if args.enable_lifecycle && !config.tenants.iter().any(|t| t.admin) {
+        return Err(Failure::config("--enable-lifecycle needs an admin tenant to authenticate /-/quit"));
+    }
 
-    let mut report = String::new();
+    lifecycle::banner(
+        &config,
+        &[
+            ("listen", if args.no_listen { "off".to_string() } else { format!("{:?} port {}", args.bind, args.port) }),
+            ("config", args.config.as_ref().map_or("none".to_string(), |p| p.display().to_string())),
+            ("cache_ttl", format!("{}s", args.cache_ttl)),
+            ("modbus_timeout", format!("{}s", args.modbus_timeout)),
+            ("modbus_retries", args.modbus_retries.to_string()),
+            ("pushgateway", args.pushgateway_url.as_deref().map_or("off".to_string(), lifecycle::redact_url)),
+            ("simulate", args.simulate.to_string()),
+        ],
+    );
+
+    let mut predictors = Predictors::new();
+    for target in config.targets.iter().filter(|t| t.predictions && !args.simulate) {
+        let client = JsonRpcClient::new(target.jsonrpc_url.as_deref(), target.host.ip(), target.auth.as_ref())
+            .map_err(Failure::config)?;
+        predictors.insert(target.device(), Predictor::new(client));
+    }
 
-    for (metric_name, labels, address, modbus_type) in MODBUS_METRICS {
-        let data = ctx
-            .read_input_registers(address, modbus_type.register_count())
-            .await;
+    let mut backends = Backends::new();
+    for target in &config.targets {
+        backends.insert(target.device(), backend::new(target).map_err(Failure::config)?);
+    }
 
-        let data = match data {
-            Ok(data) => data,
-            Err(e) => {
-                return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("unable to read modbus input register: {e}"),
-                )
-            }
-        };
+    let listeners = match args.no_listen {
+        true => Vec::new(),
+        false => listener::bind(&args.bind, args.port).map_err(Failure::Bind)?,
+    };
+    if args.validate_and_exit {
+        info!("configuration is valid, exiting");
+        return Ok(());
+    }
 
-        let value = match modbus_type {
-            U16 => decode_u16(&data).to_string(),
-            F32 => decode_f32(&data).to_string(),
-            F64 => decode_f64(&data).to_string(),
-        };
+    let adaptive_ttl = match args.slow_read_threshold {
+        Some(threshold) => Some(AdaptiveTtl {
+            threshold: Duration::try_from_secs_f64(threshold)
+                .map_err(|e| Failure::config(format!("invalid --slow-read-threshold: {e}")))?,
+            max_ttl: Duration::from_secs(args.max_cache_ttl),
+        }),
+        None => None,
+    };
 
-        let mut labels: Vec<(&str, &str)> = labels.into();
-        labels.push(("fems_id", &fems_id));
+    let state = ModbusState {
+        connections: Default::default(),
+        cache: Default::default(),
+        cache_ttl: Duration::from_secs(args.cache_ttl),
+        adaptive_ttl,
+        targets: Default::default(),
+        table: Arc::new(RwLock::new(Arc::new(registers::metric_table(&config)))),
+        battery_capacity: args.battery_capacity,
+        stream_interval: args.stream_interval,
+        relabel: Arc::new(RwLock::new(Arc::new(config.relabel))),
+        internal: Default::default(),
+        config_path: args.config.clone(),
+        modbus_timeout: Duration::from_secs(args.modbus_timeout),
+        modbus_retries: args.modbus_retries,
+        error_history: args.error_history,
+        kilowatthours: config.kilowatthours,
+        number_format: config.number_format,
+        read_window: args.read_window,
+        predictors: Arc::new(predictors),
+        windows: Default::default(),
+        inflight: Default::default(),
+        tenants: Arc::new(Tenants::new(&config.tenants, &config.targets)),
+        backends: Arc::new(backends),
+        tracer: match &config.otlp {
+            Some(otlp) if otlp.tracing => Some(otlp::start_tracing(otlp)?),
+            _ => None,
+        },
+        simulate: args.simulate,
+        missing_fems_id: config.missing_fems_id,
+        shutdown,
+        lifecycle_api: args.enable_lifecycle,
+    };
 
-        let labels: Vec<String> = labels.iter().map(|(l, v)| format!("{l} = \"{v}\"")).collect();
-        let labels = labels.join(", ");
+    if let Some(path) = &args.state_file {
+        persist::load(path, &state);
+    }
 
-        report.push_str(&format!("{metric_name}{{{labels}}} {value}\n"));
+    for target in &config.targets {
+        let mut targets = state.targets.lock().unwrap();
+        let status = targets.entry(target.device()).or_default();
+        status.auth = target.auth.as_ref().map(|a| a.method());
+        status.backend = target.backend;
+        status.sign_convention = target.sign_convention;
+        status.fems_id = Some(target.fems_id.clone());
     }
 
-    (StatusCode::OK, report)
-}
+    if args.warm_up {
+        modbus::warm_up(&state, config.targets.iter().map(|t| t.device()));
+    }
 
-#[derive(Clone)]
-struct ModbusState(Arc<Mutex<HashMap<SocketAddr, Context>>>);
+    let meter_provider = match &config.otlp {
+        Some(otlp) => {
+            let snapshot = poller::spawn(state.clone(), config.targets.clone(), otlp);
+            Some(otlp::start(otlp, &state.table(), snapshot)?)
+        }
+        None => None,
+    };
 
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    #[arg(short, long, default_value_t = 80)]
-    port: u16,
-    #[arg(short, long, default_value_t = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)))]
-    bind: IpAddr,
-}
+    if let Some(downsampling) = &config.downsampling {
+        downsample::spawn(state.clone(), config.targets.clone(), downsampling);
+    }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    tracing_subscriber::fmt::init();
+    if let Some(pushgateway) = pushgateway {
+        let period = Duration::from_secs(args.pushgateway_interval.max(1));
+        push::spawn(state.clone(), config.targets.clone(), pushgateway, period);
+    }
 
-    let args = Args::parse();
-    let bind_address = SocketAddr::new(args.bind, args.port);
+    let mut metrics_route = get(metrics);
+    if let Some(concurrency) = args.max_concurrent_scrapes {
+        let limit = ScrapeLimit::new(concurrency, args.scrape_queue_depth, state.internal.clone());
+        metrics_route = metrics_route.layer(middleware::from_fn_with_state(limit, limit::limit));
+    }
 
     let app = Router::new()
-        .route("/metrics", get(metrics))
-        .with_state(ModbusState(Arc::new(Mutex::new(HashMap::new()))));
+        .route("/metrics", metrics_route)
+        .route("/stream", get(stream::stream))
+        .route("/targets", get(targets::targets))
+        .route("/exporter/metrics", get(internal_metrics))
+        .nest("/api/v1", api::router())
+        .merge(lifecycle::router())
+        .with_state(state.clone());
+
+    let shutdown = shutdown_signal(state.shutdown.clone()).shared();
+    let timeouts = Timeouts {
+        header_read: Duration::from_secs(args.http_timeout.max(1)),
+        keep_alive: Duration::from_secs(args.http_timeout.max(1)),
+    };
+    let servers = listeners
+        .into_iter()
+        .map(|listener| server::serve(listener, app.clone(), timeouts, shutdown.clone()))
+        .collect::<Vec<_>>();
+
+    if servers.is_empty() {
+        shutdown.await;
+    } else {
+        future::try_join_all(servers).await?;
+    }
 
-    axum::Server::bind(&bind_address)
-        .serve(app.into_make_service())
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+    info!("shutting down");
+    if let Some(path) = &args.state_file {
+        persist::save(path, &state)?;
+    }
 
+    if let Some(provider) = meter_provider {
+        provider.shutdown().map_err(|e| Failure::Runtime(e.into()))?;
+    }
+    if state.tracer.is_some() {
+        opentelemetry::global::shutdown_tracer_provider();
+    }
+
+    info!("stopped");
     Ok(())
 }
 
-async fn shutdown_signal() {
+async fn shutdown_signal(shutdown: Arc<Shutdown>) {
     let ctrl_c = async {
         signal::ctrl_c()
             .await
@@ -211,8 +510,9 @@ async fn shutdown_signal() {
     tokio::select! {
         _ = ctrl_c => {},
         _ = terminate => {},
+        _ = shutdown.quit_requested() => {},
     }
+    shutdown.stopping();
 
-    println!("signal received, starting graceful shutdown");
+    info!("shutdown requested, starting graceful shutdown");
 }
-
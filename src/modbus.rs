@@ -0,0 +1,1601 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt, io,
+    net::{AddrParseError, IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use futures::future::{BoxFuture, FutureExt, Shared};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use tokio::{
+    net::TcpStream,
+    sync::Mutex,
+    time::{sleep, timeout},
+};
+use opentelemetry_sdk::trace::Tracer;
+use tokio_modbus::{client::Context, prelude::*};
+use tracing::{debug, info, warn};
+
+use crate::{
+    backend::{Backend, BackendKind, Backends, Framing, ModbusTcp, Reading},
+    downsample::Windows,
+    error::{Exception, ReadError},
+    exposition::{scale_energy, Kilowatthours, NumberFormat},
+    internal::InternalMetrics,
+    lifecycle::Shutdown,
+    modules::{Count, MAX_MODULES, MAX_TOWERS},
+    nature::{self, ComponentMap, WELL_KNOWN_COMPONENTS},
+    predictions::{self, Predictors, PREDICTION_METRICS},
+    registers::{
+        default_address, Bitfield, Coil, DiscreteInput, Group, MetricDef, MetricKind, Space, F32, F64, IO_COMPONENT, U16,
+    },
+    relabel::{relabel, RelabelRule},
+    simulate::Simulated,
+    tenants::Tenants,
+};
+
+fn decode_u16(data: &[u16]) -> u16 {
+    *data.first().unwrap()
+}
+
+fn decode_f32(data: &[u16]) -> f32 {
+    let bytes: [u8; 4] = data
+        .iter()
+        .flat_map(|word| word.to_be_bytes())
+        .collect::<Vec<u8>>()
+        .try_into()
+        .unwrap();
+    f32::from_be_bytes(bytes)
+}
+
+fn decode_f64(data: &[u16]) -> f64 {
+    let bytes: [u8; 8] = data
+        .iter()
+        .flat_map(|word| word.to_be_bytes())
+        .collect::<Vec<u8>>()
+        .try_into()
+        .unwrap();
+    f64::from_be_bytes(bytes)
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum Value {
+    U16(u16),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+}
+
+impl Value {
+    pub fn as_f64(&self) -> f64 {
+        match *self {
+            Value::U16(v) => v.into(),
+            Value::F32(v) => v.into(),
+            Value::F64(v) => v,
+            Value::Bool(v) => f64::from(u8::from(v)),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::U16(v) => v.fmt(f),
+            Value::F32(v) => v.fmt(f),
+            Value::F64(v) => v.fmt(f),
+            Value::Bool(v) => u8::from(*v).fmt(f),
+        }
+    }
+}
+
+/// Metrics computed by [`derive_samples`], all of them are gauges without labels.
+const DERIVED_METRICS: [&str; 2] = [
+    "fems_ess_energy_remaining_watthours",
+    "fems_ess_energy_to_full_watthours",
+];
+
+/// How a target signs grid and ESS power.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignConvention {
+    /// Grid power is positive while buying and negative while feeding in, ESS power is positive
+    /// while discharging, as defined by OpenEMS
+    #[default]
+    Openems,
+    /// Both the other way around, as reported by some firmware versions
+    Inverted,
+}
+
+/// How scrapes without a `fems_id` of devices that aren't configured are labeled.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingFemsId {
+    /// The address of the device, e.g. `192.168.1.10:502`, followed by the unit ID unless it's 1
+    #[default]
+    Host,
+    /// No `fems_id` label at all
+    Omit,
+}
+
+/// Power metrics whose sign depends on the [`SignConvention`] of the target.
+const SIGNED_METRICS: [&str; 5] = [
+    "fems_grid_power_watts_total",
+    "fems_grid_power_watts",
+    "fems_ess_power_watts_total",
+    "fems_ess_power_watts",
+    "fems_ess_discharge_power_watts_total",
+];
+
+/// Flips the signed power samples of a target with an inverted convention, so every target is
+/// exported in the OpenEMS convention.
+fn normalize_signs(groups: &mut [GroupSamples], convention: SignConvention) {
+    if convention == SignConvention::Openems {
+        return;
+    }
+
+    let samples = groups.iter_mut().flat_map(|(_, s)| s);
+    for sample in samples.filter(|s| SIGNED_METRICS.contains(&s.name.as_str())) {
+        sample.value = match sample.value {
+            Value::F32(v) => Value::F32(-v),
+            Value::F64(v) => Value::F64(-v),
+            value => value,
+        };
+    }
+}
+
+/// Set to 1 while values restored from the state file are served instead of fresh ones.
+pub const STALE_METRIC: &str = "fems_stale";
+
+/// Lists every metric name that can be part of a report together with its kind.
+pub fn metric_names(table: &[Group]) -> Vec<(String, MetricKind)> {
+    let mut names: Vec<(String, MetricKind)> = Vec::new();
+
+    let table = table
+        .iter()
+        .flat_map(|g| &g.metrics)
+        .map(|m| (m.name.as_str(), m.kind));
+    let derived = DERIVED_METRICS
+        .iter()
+        .chain([&STALE_METRIC])
+        .chain(PREDICTION_METRICS.iter().map(|(_, name)| name))
+        .map(|name| (*name, MetricKind::Gauge));
+
+    for (name, kind) in table.chain(derived) {
+        if !names.iter().any(|(n, _)| n == name) {
+            names.push((name.to_string(), kind));
+        }
+    }
+
+    names
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Sample {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub value: Value,
+}
+
+/// A sample of a specific FEMS, ready to be relabeled and rendered.
+pub struct Series {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub value: Value,
+}
+
+impl Sample {
+    /// Labels the sample with `fems_id`, an empty one leaves the label out.
+    pub fn to_series(&self, fems_id: &str) -> Series {
+        let mut labels = self.labels.clone();
+        if !fems_id.is_empty() {
+            labels.push(("fems_id".to_string(), fems_id.to_string()));
+        }
+
+        Series {
+            name: self.name.clone(),
+            labels,
+            value: self.value,
+        }
+    }
+}
+
+/// Computes metrics that are not read from a register but derived from other samples.
+fn derive_samples(samples: &[Sample], battery_capacity: Option<f64>) -> Vec<Sample> {
+    let find = |name| samples.iter().find(|s| s.name == name).map(|s| s.value.as_f64());
+
+    // A configured capacity takes precedence, FEMS reports 0 if it doesn't know the capacity
+    let capacity = battery_capacity.or_else(|| find("fems_ess_capacity_watthours").filter(|c| *c > 0.0));
+
+    let (Some(soc), Some(capacity)) = (find("fems_ess_soc_percent"), capacity) else {
+        return Vec::new();
+    };
+
+    let remaining = capacity * soc / 100.0;
+
+    let [remaining_name, to_full_name] = DERIVED_METRICS;
+
+    vec![
+        Sample {
+            name: remaining_name.to_string(),
+            labels: Vec::new(),
+            value: Value::F64(remaining),
+        },
+        Sample {
+            name: to_full_name.to_string(),
+            labels: Vec::new(),
+            value: Value::F64(capacity - remaining),
+        },
+    ]
+}
+
+/// Port of the FEMS Modbus/TCP API, used if a host is given without one.
+const MODBUS_PORT: u16 = 502;
+
+/// Parses `ip:port`, `[ipv6]:port` or a bare IPv4/IPv6 address.
+fn parse_host(host: &str) -> Result<SocketAddr, AddrParseError> {
+    host.parse().or_else(|e| {
+        let ip = host
+            .strip_prefix('[')
+            .and_then(|h| h.strip_suffix(']'))
+            .unwrap_or(host);
+
+        ip.parse::<IpAddr>()
+            .map(|ip| SocketAddr::new(ip, MODBUS_PORT))
+            .map_err(|_| e)
+    })
+}
+
+pub fn deserialize_host<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SocketAddr, D::Error> {
+    let host = String::deserialize(deserializer)?;
+    parse_host(&host).map_err(|_| {
+        de::Error::custom(format!("invalid host {host:?}, expected ip:port, [ipv6]:port or ip"))
+    })
+}
+
+/// Unit id FEMS answers on unless configured otherwise.
+pub fn default_unit_id() -> u8 {
+    1
+}
+
+/// Modbus device to read from, several devices may share one TCP connection.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Device {
+    pub host: SocketAddr,
+    pub unit_id: u8,
+}
+
+/// Samples of one register group, keyed by its name.
+pub type GroupSamples = (String, Vec<Sample>);
+
+/// Last successfully read samples of a device.
+pub struct CacheEntry {
+    /// `None` if the samples were restored from the state file and are stale
+    pub read_at: Option<Instant>,
+    pub samples: Vec<Sample>,
+}
+
+type Cache = HashMap<Device, CacheEntry>;
+
+/// A read of a device that several scrapes can await.
+pub type SharedRead = Shared<BoxFuture<'static, Result<Vec<Sample>, ReadError>>>;
+
+/// The single Modbus connection to a host, `None` while disconnected.
+///
+/// Holding the lock is the permit to talk to the host. OpenEMS only accepts a limited number of
+/// Modbus clients, so concurrent scrapes queue up here in FIFO order instead of connecting again.
+pub type Connection = Arc<Mutex<Option<Context>>>;
+
+/// Raising the cache TTL of devices that answer slowly, so monitoring doesn't add to their load.
+#[derive(Clone, Copy)]
+pub struct AdaptiveTtl {
+    /// Reads taking longer double the cache TTL of the device
+    pub threshold: Duration,
+    pub max_ttl: Duration,
+}
+
+#[derive(Clone, Default)]
+pub struct ModbusState {
+    pub connections: Arc<std::sync::Mutex<HashMap<SocketAddr, Connection>>>,
+    /// Recently read samples, shared by all scrapes of a device regardless of their fems_id
+    pub cache: Arc<std::sync::Mutex<Cache>>,
+    pub cache_ttl: Duration,
+    /// Set if the cache TTL of slow devices is raised
+    pub adaptive_ttl: Option<AdaptiveTtl>,
+    /// Every device that is configured or has been scraped
+    pub targets: Arc<std::sync::Mutex<HashMap<Device, TargetStatus>>>,
+    /// Metrics to read from every device, replaced when the config is reloaded
+    pub table: Arc<std::sync::RwLock<Arc<Vec<Group>>>>,
+    pub battery_capacity: Option<f64>,
+    pub stream_interval: u64,
+    pub relabel: Arc<std::sync::RwLock<Arc<Vec<RelabelRule>>>>,
+    pub internal: Arc<InternalMetrics>,
+    /// Config file to read again on reload
+    pub config_path: Option<PathBuf>,
+    /// Time a read of a device may take, including connecting
+    pub modbus_timeout: Duration,
+    /// Times a read is repeated after a timeout or a lost connection
+    pub modbus_retries: u32,
+    /// Failed reads kept per device in [`TargetStatus::recent_errors`]
+    pub error_history: usize,
+    pub kilowatthours: Kilowatthours,
+    pub number_format: NumberFormat,
+    /// Registers adjacent metrics are coalesced into a single read up to, `None` reads each metric alone
+    pub read_window: Option<u16>,
+    /// Predictor managers of the configured targets with predictions enabled
+    pub predictors: Arc<Predictors>,
+    /// Aggregates of the downsampled metrics since the last scrape
+    pub windows: Windows,
+    /// Reads of devices in progress, awaited by every scrape of the device meanwhile
+    pub inflight: Arc<std::sync::Mutex<HashMap<Device, SharedRead>>>,
+    pub tenants: Arc<Tenants>,
+    /// Backends of the configured targets, other devices are read via Modbus/TCP
+    pub backends: Arc<Backends>,
+    /// Set if scrapes are traced, see [`crate::otlp::ScrapeSpan`]
+    pub tracer: Option<Tracer>,
+    /// Serve synthetic values for every device instead of reading it
+    pub simulate: bool,
+    pub missing_fems_id: MissingFemsId,
+    pub shutdown: Arc<Shutdown>,
+    /// Whether `/-/quit` may be used
+    pub lifecycle_api: bool,
+}
+
+impl ModbusState {
+    pub fn table(&self) -> Arc<Vec<Group>> {
+        self.table.read().unwrap().clone()
+    }
+
+    /// Replaces the metric table and relabel rules, reads in progress finish with the old ones.
+    pub fn reload(&self, table: Vec<Group>, relabel: Vec<RelabelRule>) {
+        *self.table.write().unwrap() = Arc::new(table);
+        *self.relabel.write().unwrap() = Arc::new(relabel);
+    }
+
+    /// Turns the samples of the FEMS identified by `fems_id` into relabeled series.
+    pub fn series(&self, samples: &[Sample], fems_id: &str) -> Vec<Series> {
+        let rules = self.relabel.read().unwrap().clone();
+        let series = samples.iter().map(|s| s.to_series(fems_id)).collect();
+        let mut series = scale_energy(series, self.kilowatthours);
+        relabel(&rules, &mut series);
+        series
+    }
+
+    /// Appends the samples derived from the read ones.
+    pub fn with_derived(&self, mut samples: Vec<Sample>) -> Vec<Sample> {
+        let derived = derive_samples(&samples, self.battery_capacity);
+        samples.extend(derived);
+        samples
+    }
+
+    /// Samples describing how reading `device` went, to be exported next to its values.
+    pub fn scrape_samples(&self, device: Device) -> Vec<Sample> {
+        let targets = self.targets.lock().unwrap();
+        let Some(status) = targets.get(&device) else {
+            return Vec::new();
+        };
+
+        let sample = |name: &str, value| Sample {
+            name: name.to_string(),
+            labels: Vec::new(),
+            value: Value::F64(value),
+        };
+
+        let mut samples = Vec::new();
+        if let Some(duration) = status.scrape_duration {
+            samples.push(sample("fems_scrape_duration_seconds", duration));
+        }
+        samples.push(sample("fems_scrape_registers_read", f64::from(status.registers_read)));
+        samples.push(sample("fems_scrape_errors", status.scrape_errors as f64));
+        if self.adaptive_ttl.is_some() {
+            let ttl = status.adapted_cache_ttl.unwrap_or(self.cache_ttl.as_secs_f64());
+            samples.push(sample("fems_scrape_cache_ttl_seconds", ttl));
+        }
+        for (metric, resets) in &status.counter_resets {
+            samples.push(Sample {
+                name: "fems_counter_reset_total".to_string(),
+                labels: vec![("metric".to_string(), metric.clone())],
+                value: Value::F64(*resets as f64),
+            });
+        }
+        samples
+    }
+
+    /// The `fems_id` of scrapes of `device` that don't give one, empty if the label is left out.
+    ///
+    /// Configured targets always use their configured `fems_id`.
+    pub fn default_fems_id(&self, device: Device) -> String {
+        let configured = self.targets.lock().unwrap().get(&device).and_then(|t| t.fems_id.clone());
+        if let Some(fems_id) = configured {
+            return fems_id;
+        }
+
+        match self.missing_fems_id {
+            MissingFemsId::Host if device.unit_id == default_unit_id() => device.host.to_string(),
+            MissingFemsId::Host => format!("{}/{}", device.host, device.unit_id),
+            MissingFemsId::Omit => String::new(),
+        }
+    }
+
+    /// The backend `device` is read with.
+    pub fn backend(&self, device: Device) -> Arc<dyn Backend> {
+        if self.simulate {
+            return Arc::new(Simulated);
+        }
+        self.backends
+            .get(&device)
+            .cloned()
+            .unwrap_or_else(|| Arc::new(ModbusTcp))
+    }
+
+    /// Whether a failed read attempt should be repeated, counting the attempt if so.
+    pub fn retry(&self, device: Device, error: &ReadError, attempt: &mut u32) -> bool {
+        if !error.is_transient() || *attempt >= self.modbus_retries {
+            return false;
+        }
+
+        *attempt += 1;
+        info!(host = %device.host, unit_id = device.unit_id, attempt, kind = error.kind(), "retrying read: {error}");
+        true
+    }
+
+    /// One `fems_debug_register_value` sample per register last read from `device`.
+    pub fn debug_samples(&self, device: Device) -> Vec<Sample> {
+        let targets = self.targets.lock().unwrap();
+        let Some(status) = targets.get(&device) else {
+            return Vec::new();
+        };
+
+        let mut samples = Vec::new();
+        for (address, words) in &status.raw_registers {
+            for (word, value) in words.iter().enumerate() {
+                samples.push(Sample {
+                    name: "fems_debug_register_value".to_string(),
+                    labels: vec![("address".to_string(), address.to_string()), ("word".to_string(), word.to_string())],
+                    value: Value::U16(*value),
+                });
+            }
+        }
+        samples
+    }
+
+    fn connection(&self, host: SocketAddr) -> Connection {
+        let mut connections = self.connections.lock().unwrap();
+        connections.entry(host).or_default().clone()
+    }
+
+    /// Cache TTL of `device`, raised while it answers slowly.
+    fn cache_ttl(&self, device: Device) -> Duration {
+        let targets = self.targets.lock().unwrap();
+        let adapted = targets.get(&device).and_then(|t| t.adapted_cache_ttl);
+        adapted.map_or(self.cache_ttl, Duration::from_secs_f64)
+    }
+
+    /// Whether `device` was read more recently than its raised cache TTL, so periodic reads
+    /// should skip it.
+    pub fn throttled(&self, device: Device) -> bool {
+        let targets = self.targets.lock().unwrap();
+        let Some(status) = targets.get(&device) else {
+            return false;
+        };
+
+        match (status.adapted_cache_ttl, status.last_scrape) {
+            (Some(ttl), Some(last_scrape)) => (unix_now().saturating_sub(last_scrape) as f64) < ttl,
+            _ => false,
+        }
+    }
+
+    fn cached(&self, device: Device) -> Option<Vec<Sample>> {
+        let ttl = self.cache_ttl(device);
+        let cache = self.cache.lock().unwrap();
+        cache
+            .get(&device)
+            .filter(|e| e.read_at.is_some_and(|read_at| read_at.elapsed() < ttl))
+            .map(|e| e.samples.clone())
+    }
+
+    /// Samples restored from the state file, as long as no fresh read succeeded.
+    fn restored(&self, device: Device) -> Option<Vec<Sample>> {
+        let cache = self.cache.lock().unwrap();
+        let entry = cache.get(&device).filter(|e| e.read_at.is_none())?;
+
+        let mut samples = entry.samples.clone();
+        samples.push(Sample {
+            name: STALE_METRIC.to_string(),
+            labels: Vec::new(),
+            value: Value::U16(1),
+        });
+        Some(samples)
+    }
+}
+
+/// Availability of each register group of a device, as discovered on first contact.
+pub type SupportMap = BTreeMap<String, GroupSupport>;
+
+#[derive(Clone, Serialize)]
+pub struct GroupSupport {
+    pub supported: bool,
+    /// Exception the device answered with when the group was probed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exception: Option<Exception>,
+}
+
+/// Exception code devices answer reads with that are longer than they support.
+const ILLEGAL_DATA_VALUE: u8 = 0x03;
+
+/// A metric to read at its relocated address, with the labels of its position.
+struct PlannedRead<'a> {
+    address: u16,
+    metric: &'a MetricDef,
+    extra: &'a [(String, String)],
+}
+
+impl PlannedRead<'_> {
+    /// Address after the last register of the metric.
+    fn end(&self) -> u32 {
+        u32::from(self.address) + u32::from(self.metric.modbus_type.register_count())
+    }
+}
+
+/// Reads all registers of `group`, with the component block starting at `base`.
+///
+/// Metrics at adjacent addresses are read together, as long as the read isn't longer than
+/// `window` registers. If the device rejects such a read as illegal data value, `window` is
+/// halved and the read retried.
+///
+/// Returns the samples and the number of registers read.
+async fn read_group(
+    ctx: &mut Context,
+    group: &Group,
+    base: u16,
+    window: &mut u16,
+    raw: &mut RawRegisters,
+) -> io::Result<(Vec<Sample>, u32)> {
+    let default_base = default_address(&group.component).unwrap_or_default();
+    let relocate = |address: u16| {
+        address
+            .checked_sub(default_base)
+            .and_then(|offset| offset.checked_add(base))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "register address out of range"))
+    };
+
+    let Some(layout) = &group.modules else {
+        let reads = group
+            .metrics
+            .iter()
+            .map(|metric| Ok(PlannedRead { address: relocate(metric.address)?, metric, extra: &[] }))
+            .collect::<io::Result<Vec<_>>>()?;
+        return read_planned(ctx, &reads, window, raw).await;
+    };
+
+    let towers = read_count(ctx, layout.towers, relocate, MAX_TOWERS).await?;
+    let modules = read_count(ctx, layout.modules, relocate, MAX_MODULES).await?;
+
+    let positions: Vec<[(String, String); 2]> = (0..towers)
+        .flat_map(|tower| {
+            (0..modules).map(move |module| {
+                [
+                    ("tower".to_string(), tower.to_string()),
+                    ("module".to_string(), module.to_string()),
+                ]
+            })
+        })
+        .collect();
+
+    let mut reads = Vec::new();
+    for (i, position) in positions.iter().enumerate() {
+        let (tower, module) = (i as u16 / modules, i as u16 % modules);
+        for metric in &group.metrics {
+            let address = layout
+                .address(tower, module, metric.address)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "module address out of range"))?;
+            reads.push(PlannedRead { address: relocate(address)?, metric, extra: position });
+        }
+    }
+
+    read_planned(ctx, &reads, window, raw).await
+}
+
+/// Number of towers or modules, counts read from the device are capped to `max`.
+async fn read_count(
+    ctx: &mut Context,
+    count: Count,
+    relocate: impl Fn(u16) -> io::Result<u16>,
+    max: u16,
+) -> io::Result<u16> {
+    match count {
+        Count::Fixed(count) => Ok(count),
+        Count::Register { address } => {
+            let data = ctx.read_input_registers(relocate(address)?, 1).await?;
+            Ok(decode_u16(&data).min(max))
+        }
+    }
+}
+
+/// Reads the planned metrics in order, coalescing adjacent ones into reads of up to `window`
+/// registers. The registers of every metric are also kept in `raw`.
+async fn read_planned(
+    ctx: &mut Context,
+    reads: &[PlannedRead<'_>],
+    window: &mut u16,
+    raw: &mut RawRegisters,
+) -> io::Result<(Vec<Sample>, u32)> {
+    let mut samples = Vec::new();
+    let mut registers_read = 0;
+
+    let mut i = 0;
+    while i < reads.len() {
+        let start = reads[i].address;
+        let space = reads[i].metric.modbus_type.space();
+        let (next, end) = coalesce(reads, i, *window);
+
+        // A single metric is always read as a whole
+        let count = (end - u32::from(start)) as u16;
+        let data = match read_space(ctx, space, start, count).await {
+            Ok(data) => data,
+            Err(e) if next - i > 1 && Exception::from_io(&e).is_some_and(|e| e.code == ILLEGAL_DATA_VALUE) => {
+                *window = (count / 2).max(1);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if data.len() != usize::from(count) {
+            let message = format!("expected {count} registers at {start}, got {}", data.len());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+
+        for read in &reads[i..next] {
+            let offset = usize::from(read.address - start);
+            let data = &data[offset..offset + usize::from(read.metric.modbus_type.register_count())];
+            decode_metric(read.metric, data, read.extra, &mut samples);
+            if space == Space::InputRegisters {
+                raw.insert(read.address, data.to_vec());
+            }
+        }
+        registers_read += u32::from(count);
+        i = next;
+    }
+
+    Ok((samples, registers_read))
+}
+
+/// Coalesces `reads[first]` with the adjacent reads following it in the same space, as long as
+/// they span at most `window` registers. Returns the index and the address after the last one.
+fn coalesce(reads: &[PlannedRead<'_>], first: usize, window: u16) -> (usize, u32) {
+    let start = u32::from(reads[first].address);
+    let space = reads[first].metric.modbus_type.space();
+    let mut end = reads[first].end();
+    let mut next = first + 1;
+
+    while let Some(read) = reads.get(next) {
+        let adjacent = u32::from(read.address) == end && read.metric.modbus_type.space() == space;
+        if !adjacent || read.end() - start > u32::from(window) {
+            break;
+        }
+        end = read.end();
+        next += 1;
+    }
+
+    (next, end)
+}
+
+/// Reads `count` registers or bits from `space`, bits as registers of 0 or 1.
+async fn read_space(ctx: &mut Context, space: Space, start: u16, count: u16) -> io::Result<Vec<u16>> {
+    let bits = match space {
+        Space::InputRegisters => return ctx.read_input_registers(start, count).await,
+        Space::Coils => ctx.read_coils(start, count).await?,
+        Space::DiscreteInputs => ctx.read_discrete_inputs(start, count).await?,
+    };
+
+    // Bits come in whole bytes, the padding isn't part of the response
+    Ok(bits.into_iter().take(usize::from(count)).map(u16::from).collect())
+}
+
+/// Decodes the registers of `metric` and appends its samples with the `extra` labels.
+pub fn decode_metric(metric: &MetricDef, data: &[u16], extra: &[(String, String)], samples: &mut Vec<Sample>) {
+    let MetricDef { name, labels, modbus_type, bits, .. } = metric;
+
+    let mut labels = labels.clone();
+    labels.extend_from_slice(extra);
+
+    let value = match modbus_type {
+        U16 => Value::U16(decode_u16(data)),
+        F32 => Value::F32(decode_f32(data)),
+        F64 => Value::F64(decode_f64(data)),
+        Coil | DiscreteInput => Value::Bool(data[0] != 0),
+        Bitfield => {
+            let flags = decode_u16(data);
+            for (bit, code) in bits {
+                let mut labels = labels.clone();
+                labels.push(("code".to_string(), code.clone()));
+
+                samples.push(Sample {
+                    name: name.clone(),
+                    labels,
+                    value: Value::Bool(flags & (1 << bit) != 0),
+                });
+            }
+            return;
+        }
+    };
+
+    samples.push(Sample {
+        name: name.clone(),
+        labels,
+        value,
+    });
+}
+
+/// Registers of every metric as last read, keyed by the address of its first register.
+pub type RawRegisters = BTreeMap<u16, Vec<u16>>;
+
+/// What the exporter knows about a device it has been asked to read.
+#[derive(Clone, Default, Serialize)]
+pub struct TargetStatus {
+    pub connected: bool,
+    /// Unix timestamp of the last read attempt that wasn't answered from the cache
+    pub last_scrape: Option<u64>,
+    /// The `fems_id` of the configured target
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fems_id: Option<String>,
+    pub last_error: Option<ReadError>,
+    /// Last failed reads, oldest first, up to `--error-history` of them
+    pub recent_errors: VecDeque<ErrorRecord>,
+    pub consecutive_failures: u32,
+    pub register_groups: SupportMap,
+    /// Base addresses located in the OpenEMS component table on connect
+    pub components: Option<ComponentMap>,
+    /// Seconds the last successful read of the device took
+    pub scrape_duration: Option<f64>,
+    /// Seconds samples are cached for while the device answers slowly, see [`AdaptiveTtl`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adapted_cache_ttl: Option<f64>,
+    /// Number of registers read in the last successful read
+    pub registers_read: u32,
+    /// Number of failed reads since the exporter started
+    pub scrape_errors: u64,
+    /// Number of times each counter was reset, e.g. by a firmware update
+    pub counter_resets: BTreeMap<String, u64>,
+    /// Last read value of every counter series, to detect resets
+    #[serde(skip)]
+    pub counters: HashMap<(String, Vec<(String, String)>), f64>,
+    /// Longest read the device accepts, known once it rejected a longer one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_window: Option<u16>,
+    /// Undecoded registers of the last reads, exported with `debug=true`
+    #[serde(skip)]
+    pub raw_registers: RawRegisters,
+    /// How the target authenticates, if it's configured with credentials
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<&'static str>,
+    pub backend: BackendKind,
+    pub sign_convention: SignConvention,
+}
+
+/// A failed read of a device, kept after the device recovered.
+#[derive(Clone, Serialize)]
+pub struct ErrorRecord {
+    /// Unix timestamp of the read
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub error: ReadError,
+    /// The error as it's logged, the flattened error has its own `message`
+    pub description: String,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Reads all metrics from `device`, reusing an existing connection if there is one.
+///
+/// Samples read less than `cache_ttl` ago are returned without contacting the device. Scrapes
+/// arriving while the device is being read share the result of that read. On first contact,
+/// register groups the device answers with an exception are recorded as unsupported and skipped
+/// from then on. The read itself runs as its own task, it's finished even if the scrape isn't.
+pub async fn read_samples(state: &ModbusState, device: Device) -> Result<Vec<Sample>, ReadError> {
+    if let Some(samples) = state.cached(device) {
+        return Ok(samples);
+    }
+
+    let read = {
+        let mut inflight = state.inflight.lock().unwrap();
+        inflight
+            .entry(device)
+            .or_insert_with(|| {
+                // Spawned, so the read goes on and releases the connection also if every scrape
+                // waiting for it is cancelled
+                let state = state.clone();
+                let read = tokio::spawn(async move {
+                    let result = read_fresh(&state, device).await;
+                    state.inflight.lock().unwrap().remove(&device);
+                    result
+                });
+                async move { read.await.expect("read of the device panicked") }.boxed().shared()
+            })
+            .clone()
+    };
+
+    read.await
+}
+
+/// Reads `device` with its backend and caches the samples.
+///
+/// Scrapes of the device meanwhile share this read, see [`read_samples`], and the cache is filled
+/// before they stop doing so.
+async fn read_fresh(state: &ModbusState, device: Device) -> Result<Vec<Sample>, ReadError> {
+    match read_recorded(state, device, None).await {
+        Ok(groups) => {
+            let mut samples = state.with_derived(groups.into_iter().flat_map(|(_, s)| s).collect());
+            samples.extend(predictions::read(state, device).await);
+
+            // Also kept with caching disabled, as the last known good values for the state file
+            let mut cache = state.cache.lock().unwrap();
+            let entry = CacheEntry {
+                read_at: Some(Instant::now()),
+                samples: samples.clone(),
+            };
+            cache.insert(device, entry);
+
+            Ok(samples)
+        }
+        Err(e) => {
+            if let Some(samples) = state.restored(device) {
+                warn!(host = %device.host, unit_id = device.unit_id, kind = e.kind(), "serving restored values: {e}");
+                return Ok(samples);
+            }
+
+            Err(e)
+        }
+    }
+}
+
+/// Reads only the register groups in `due` from `device`, bypassing the cache.
+///
+/// Derived samples are not included, see [`ModbusState::with_derived`].
+pub async fn read_groups(
+    state: &ModbusState,
+    device: Device,
+    due: &HashSet<String>,
+) -> Result<Vec<GroupSamples>, ReadError> {
+    read_recorded(state, device, Some(due)).await
+}
+
+/// Doubles the cache TTL of a device whose read took longer than the threshold, and halves it
+/// again once reads take less than half of it.
+///
+/// Reads in between keep the TTL, so a device hovering around the threshold doesn't flip back
+/// and forth.
+fn adapt_cache_ttl(status: &mut TargetStatus, device: Device, adaptive: AdaptiveTtl, base: Duration, took: Duration) {
+    let current = status.adapted_cache_ttl.map_or(base, Duration::from_secs_f64);
+    let adapted = if took > adaptive.threshold {
+        (current.max(Duration::from_secs(1)) * 2).min(adaptive.max_ttl)
+    } else if took < adaptive.threshold / 2 {
+        current / 2
+    } else {
+        return;
+    };
+
+    if adapted <= base {
+        if status.adapted_cache_ttl.take().is_some() {
+            info!(host = %device.host, unit_id = device.unit_id, "device answers quickly again, cache TTL back to {}s", base.as_secs_f64());
+        }
+    } else if status.adapted_cache_ttl != Some(adapted.as_secs_f64()) {
+        info!(
+            host = %device.host,
+            unit_id = device.unit_id,
+            read_seconds = took.as_secs_f64(),
+            "device answers slowly, cache TTL now {}s",
+            adapted.as_secs_f64()
+        );
+        status.adapted_cache_ttl = Some(adapted.as_secs_f64());
+    }
+}
+
+/// Share of its previous value a counter has to drop by to be considered reset, smaller
+/// decreases are taken as noise
+const COUNTER_RESET_DROP: f64 = 0.1;
+
+/// Counts and logs a reset if the counter `sample` dropped sharply since the last read.
+fn detect_counter_reset(status: &mut TargetStatus, device: Device, sample: &Sample) {
+    let current = sample.value.as_f64();
+    let key = (sample.name.clone(), sample.labels.clone());
+    let Some(previous) = status.counters.insert(key, current) else {
+        return;
+    };
+
+    if current < previous * (1.0 - COUNTER_RESET_DROP) {
+        *status.counter_resets.entry(sample.name.clone()).or_default() += 1;
+        warn!(
+            event = "counter_reset",
+            host = %device.host,
+            unit_id = device.unit_id,
+            metric = sample.name,
+            previous,
+            current,
+            "counter decreased, it was probably reset"
+        );
+    }
+}
+
+/// Reads `device` with its backend and records the outcome in its [`TargetStatus`].
+///
+/// Reads of only the groups in `due`, by the background pollers, keep the scrape metadata, the
+/// cache TTL and the counters checked for resets of the last full read; they only update the
+/// connection state and the errors.
+async fn read_recorded(
+    state: &ModbusState,
+    device: Device,
+    due: Option<&HashSet<String>>,
+) -> Result<Vec<GroupSamples>, ReadError> {
+    let backend = state.backend(device);
+    let result = backend.read_metrics(state, device, due).await;
+    let full = due.is_none();
+    let now = unix_now();
+
+    let mut targets = state.targets.lock().unwrap();
+    let status = targets.entry(device).or_default();
+    status.backend = backend.kind();
+    // Backends drop their connection whenever a read fails
+    status.connected = result.is_ok();
+    if full {
+        status.last_scrape = Some(now);
+    }
+
+    match result {
+        Ok(Reading { mut groups, registers_read, duration }) => {
+            normalize_signs(&mut groups, status.sign_convention);
+
+            status.last_error = None;
+            status.consecutive_failures = 0;
+            if !full {
+                return Ok(groups);
+            }
+
+            status.scrape_duration = Some(duration.as_secs_f64());
+            status.registers_read = registers_read;
+            if let Some(adaptive) = state.adaptive_ttl {
+                adapt_cache_ttl(status, device, adaptive, state.cache_ttl, duration);
+            }
+
+            let table = state.table();
+            let counters = table.iter().flat_map(|g| &g.metrics).filter(|m| m.kind == MetricKind::Counter);
+            let counters: HashSet<&str> = counters.map(|m| m.name.as_str()).collect();
+            for sample in groups.iter().flat_map(|(_, s)| s).filter(|s| counters.contains(s.name.as_str())) {
+                detect_counter_reset(status, device, sample);
+            }
+
+            Ok(groups)
+        }
+        Err(e) => {
+            state.internal.count_read_error(device.host, &e);
+            status.last_error = Some(e.clone());
+            if state.error_history > 0 {
+                if status.recent_errors.len() >= state.error_history {
+                    status.recent_errors.pop_front();
+                }
+                status.recent_errors.push_back(ErrorRecord {
+                    timestamp: now,
+                    error: e.clone(),
+                    description: e.to_string(),
+                });
+            }
+            status.consecutive_failures += 1;
+            status.scrape_errors += 1;
+
+            Err(e)
+        }
+    }
+}
+
+/// Opens a Modbus connection to `host`, RTU frames are sent over TCP to a serial gateway.
+async fn open(framing: Framing, host: SocketAddr, slave: Slave) -> io::Result<Context> {
+    match framing {
+        Framing::Tcp => tcp::connect_slave(host, slave).await,
+        Framing::Rtu => Ok(rtu::attach_slave(TcpStream::connect(host).await?, slave)),
+    }
+}
+
+/// Uses the existing connection to the host of `device` or opens a new one.
+///
+/// Components are located whenever a connection is opened.
+async fn connect<'a>(
+    state: &ModbusState,
+    framing: Framing,
+    connection: &'a mut Option<Context>,
+    device: Device,
+) -> Result<&'a mut Context, ReadError> {
+    let Device { host, unit_id } = device;
+
+    match connection {
+        Some(ctx) => {
+            ctx.set_slave(Slave(unit_id));
+            Ok(ctx)
+        }
+        None => {
+            let mut ctx = open(framing, host, Slave(unit_id))
+                .await
+                .map_err(|e| ReadError::Connect { host, message: e.to_string() })?;
+
+            // Component addresses might have changed while we were disconnected
+            let components = locate_components(state, &mut ctx, device).await?;
+            state.targets.lock().unwrap().entry(device).or_default().components = components;
+
+            Ok(connection.insert(ctx))
+        }
+    }
+}
+
+/// Connects to the host of `device` unless already connected, see [`Backend::connect`].
+pub async fn connect_modbus(state: &ModbusState, framing: Framing, device: Device) -> Result<(), ReadError> {
+    let connection = state.connection(device.host);
+    let mut connection = connection.lock().await;
+
+    timeout(state.modbus_timeout, connect(state, framing, &mut connection, device))
+        .await
+        .unwrap_or_else(|_| Err(ReadError::timeout(state.modbus_timeout)))
+        .map(|_| ())
+}
+
+/// Reads `device` over Modbus, see [`Backend::read_metrics`].
+///
+/// Holding the connection of the host is waited for first. Timeouts and lost connections are
+/// retried on a new connection, up to `modbus_retries` times.
+pub async fn read_modbus(
+    state: &ModbusState,
+    framing: Framing,
+    device: Device,
+    due: Option<&HashSet<String>>,
+) -> Result<Reading, ReadError> {
+    let connection = state.connection(device.host);
+    let queued_at = Instant::now();
+    let mut connection = connection.lock().await;
+    state
+        .internal
+        .observe_connection_wait(device.host, queued_at.elapsed());
+
+    let started_at = Instant::now();
+    let mut attempt = 0;
+    loop {
+        let result = timeout(state.modbus_timeout, read_device(state, framing, &mut connection, device, due))
+            .await
+            .unwrap_or_else(|_| Err(ReadError::timeout(state.modbus_timeout)));
+
+        // The connection might be broken, open a new one for the next attempt
+        if result.is_err() {
+            *connection = None;
+        }
+
+        match result {
+            Ok((groups, registers_read)) => {
+                return Ok(Reading { groups, registers_read, duration: started_at.elapsed() });
+            }
+            Err(e) if state.retry(device, &e, &mut attempt) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Attempts to connect to a device during warm-up before giving up until its first scrape
+const WARM_UP_ATTEMPTS: u32 = 5;
+const WARM_UP_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Connects to all `devices` in parallel in the background, so their first scrapes don't have to.
+///
+/// Failed attempts are retried with exponential backoff. Scrapes arriving meanwhile queue up for
+/// the connection as usual.
+pub fn warm_up(state: &ModbusState, devices: impl IntoIterator<Item = Device>) {
+    for device in devices {
+        let state = state.clone();
+        let Device { host, unit_id } = device;
+
+        tokio::spawn(async move {
+            let mut delay = Duration::from_secs(1);
+
+            for attempt in 1..=WARM_UP_ATTEMPTS {
+                let result = state.backend(device).connect(&state, device).await;
+                state.targets.lock().unwrap().entry(device).or_default().connected = result.is_ok();
+
+                match result {
+                    Ok(()) => {
+                        debug!(%host, unit_id, attempt, "connected during warm-up");
+                        return;
+                    }
+                    Err(e) => {
+                        state.internal.count_read_error(host, &e);
+                        warn!(%host, unit_id, attempt, kind = e.kind(), "warm-up connect failed: {e}");
+                    }
+                }
+
+                if attempt < WARM_UP_ATTEMPTS {
+                    sleep(delay).await;
+                    delay = (delay * 2).min(WARM_UP_MAX_DELAY);
+                }
+            }
+        });
+    }
+}
+
+/// Reads the supported groups in `due` or all of them, returns their samples and the number of
+/// registers read.
+async fn read_device(
+    state: &ModbusState,
+    framing: Framing,
+    connection: &mut Option<Context>,
+    device: Device,
+    due: Option<&HashSet<String>>,
+) -> Result<(Vec<GroupSamples>, u32), ReadError> {
+    let Device { host, unit_id } = device;
+    let ctx = connect(state, framing, connection, device).await?;
+
+    let components = state
+        .targets
+        .lock()
+        .unwrap()
+        .get(&device)
+        .and_then(|t| t.components.clone())
+        .unwrap_or_default();
+
+    let (known_support, known_window) = state
+        .targets
+        .lock()
+        .unwrap()
+        .get(&device)
+        .map(|t| (t.register_groups.clone(), t.read_window))
+        .unwrap_or_default();
+    // Without a configured window every metric is read on its own
+    let mut window = state.read_window.map_or(1, |max| known_window.unwrap_or(max).min(max));
+
+    let mut discovered = SupportMap::new();
+    let mut raw = RawRegisters::new();
+    let mut groups = Vec::new();
+    let mut registers_read = 0;
+
+    for group in state.table().iter() {
+        if due.is_some_and(|due| !due.contains(&group.name)) || !group.applies_to(device) {
+            continue;
+        }
+
+        let probing = match known_support.get(&group.name) {
+            Some(GroupSupport { supported: false, .. }) => continue,
+            Some(_) => false,
+            None => true,
+        };
+
+        let base = components.get(&group.component).copied();
+        let Some(base) = base.or_else(|| default_address(&group.component)) else {
+            debug!(%host, unit_id, group = group.name, "component {} not found", group.component);
+            continue;
+        };
+
+        let tried = window;
+        let result = read_group(ctx, group, base, &mut window, &mut raw).await;
+        match &result {
+            // Kept only once the shorter reads succeeded, a group the device doesn't have may reject
+            // reads of any length
+            Ok(_) if window < tried => {
+                info!(%host, unit_id, group = group.name, "long read rejected, reading at most {window} registers at once");
+                state.targets.lock().unwrap().entry(device).or_default().read_window = Some(window);
+            }
+            Ok(_) => {}
+            Err(_) => window = tried,
+        }
+
+        match result {
+            Ok((group_samples, group_registers)) => {
+                groups.push((group.name.clone(), group_samples));
+                registers_read += group_registers;
+                if probing {
+                    discovered.insert(group.name.clone(), GroupSupport { supported: true, exception: None });
+                }
+            }
+            Err(e) => match Exception::from_io(&e) {
+                Some(exception) if (probing && exception.is_unsupported()) || exception.is_permanent() => {
+                    info!(%host, unit_id, group = group.name, "register group not supported: {exception}");
+                    discovered.insert(
+                        group.name.clone(),
+                        GroupSupport { supported: false, exception: Some(exception) },
+                    );
+                }
+                _ => return Err(e.into()),
+            },
+        }
+    }
+
+    let mut targets = state.targets.lock().unwrap();
+    let status = targets.entry(device).or_default();
+    status.register_groups.extend(discovered);
+    // Reads of some groups only replace the registers of those
+    status.raw_registers.extend(raw);
+
+    Ok((groups, registers_read))
+}
+
+/// Locates the components used by the metric table, `None` if the device has no component table.
+async fn locate_components(
+    state: &ModbusState,
+    ctx: &mut Context,
+    device: Device,
+) -> Result<Option<ComponentMap>, ReadError> {
+    let table = state.table();
+    let mut candidates: Vec<&str> = WELL_KNOWN_COMPONENTS.to_vec();
+    candidates.extend(table.iter().map(|g| g.component.as_str()).filter(|c| *c != IO_COMPONENT));
+    candidates.sort_unstable();
+    candidates.dedup();
+
+    match nature::scan(ctx, &candidates).await {
+        Ok(Some(components)) => Ok(Some(components)),
+        Ok(None) => {
+            warn!(host = %device.host, unit_id = device.unit_id, "no OpenEMS component table found, using default addresses");
+            Ok(None)
+        }
+        Err(e) => match Exception::from_io(&e) {
+            Some(exception) if exception.is_unsupported() => {
+                warn!(host = %device.host, unit_id = device.unit_id, "unable to read OpenEMS component table, using default addresses: {exception}");
+                Ok(None)
+            }
+            _ => Err(e.into()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::poll;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn device() -> Device {
+        Device { host: "127.0.0.1:502".parse().unwrap(), unit_id: 1 }
+    }
+
+    fn metric(address: u16, modbus_type: crate::registers::ModbusType) -> MetricDef {
+        MetricDef {
+            name: format!("fems_test_{address}"),
+            labels: Vec::new(),
+            address,
+            modbus_type,
+            kind: MetricKind::Gauge,
+            bits: Vec::new(),
+        }
+    }
+
+    fn planned(metrics: &[MetricDef]) -> Vec<PlannedRead<'_>> {
+        metrics.iter().map(|metric| PlannedRead { address: metric.address, metric, extra: &[] }).collect()
+    }
+
+    /// Serves Modbus/TCP where every input register below 1000 holds its address. Reads of more
+    /// than `limit` registers, or of registers from 1000 on, are rejected as illegal data value.
+    async fn serve_limited(limit: u16) -> SocketAddr {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 12];
+            while socket.read_exact(&mut request).await.is_ok() {
+                let start = u16::from_be_bytes([request[8], request[9]]);
+                let count = u16::from_be_bytes([request[10], request[11]]);
+                let pdu = match count > limit || start + count > 1000 {
+                    true => vec![0x84, ILLEGAL_DATA_VALUE],
+                    false => {
+                        let mut pdu = vec![0x04, (count * 2) as u8];
+                        (start..start + count).for_each(|address| pdu.extend_from_slice(&address.to_be_bytes()));
+                        pdu
+                    }
+                };
+
+                let mut response = request[..4].to_vec();
+                response.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+                response.push(request[6]);
+                response.extend_from_slice(&pdu);
+                socket.write_all(&response).await.unwrap();
+            }
+        });
+
+        host
+    }
+
+    fn gauge(name: &str, value: f64) -> Sample {
+        Sample { name: name.to_string(), labels: Vec::new(), value: Value::F64(value) }
+    }
+
+    fn derived(samples: &[Sample], battery_capacity: Option<f64>) -> Vec<(String, f64)> {
+        derive_samples(samples, battery_capacity).into_iter().map(|s| (s.name, s.value.as_f64())).collect()
+    }
+
+    #[test]
+    fn remaining_energy_is_derived_from_the_soc() {
+        let samples = [gauge("fems_ess_soc_percent", 40.0), gauge("fems_ess_capacity_watthours", 10000.0)];
+        let [remaining, to_full] = DERIVED_METRICS.map(str::to_string);
+
+        assert_eq!(derived(&samples, None), [(remaining.clone(), 4000.0), (to_full.clone(), 6000.0)]);
+        // The configured capacity takes precedence over the reported one
+        assert_eq!(derived(&samples, Some(5000.0)), [(remaining, 2000.0), (to_full, 3000.0)]);
+    }
+
+    #[test]
+    fn remaining_energy_needs_a_known_capacity() {
+        assert!(derived(&[gauge("fems_ess_soc_percent", 40.0)], None).is_empty());
+        // FEMS reports a capacity of 0 if it doesn't know it
+        let samples = [gauge("fems_ess_soc_percent", 40.0), gauge("fems_ess_capacity_watthours", 0.0)];
+        assert!(derived(&samples, None).is_empty());
+        assert!(derived(&[gauge("fems_ess_capacity_watthours", 10000.0)], None).is_empty());
+    }
+
+    #[test]
+    fn inverted_power_signs_are_flipped() {
+        let samples = || {
+            vec![(
+                "grid".to_string(),
+                vec![
+                    gauge("fems_grid_power_watts", -500.0),
+                    gauge("fems_ess_power_watts", 1200.0),
+                    gauge("fems_ess_soc_percent", 40.0),
+                ],
+            )]
+        };
+        let values = |groups: &[GroupSamples]| groups[0].1.iter().map(|s| s.value.as_f64()).collect::<Vec<_>>();
+
+        let mut groups = samples();
+        normalize_signs(&mut groups, SignConvention::Openems);
+        assert_eq!(values(&groups), [-500.0, 1200.0, 40.0]);
+
+        normalize_signs(&mut groups, SignConvention::Inverted);
+        assert_eq!(values(&groups), [500.0, -1200.0, 40.0]);
+    }
+
+    #[test]
+    fn error_records_keep_the_message_of_the_error() {
+        let error = ReadError::Connect { host: device().host, message: "connection refused".to_string() };
+        let record = ErrorRecord { timestamp: 1700000000, description: error.to_string(), error };
+
+        let json = serde_json::to_value(&record).unwrap();
+        assert_eq!(json["kind"], "connect");
+        assert_eq!(json["message"], "connection refused");
+        assert_eq!(json["description"], record.description);
+        assert_eq!(serde_json::to_string(&record).unwrap().matches("\"message\"").count(), 1);
+    }
+
+    #[test]
+    fn adjacent_reads_are_coalesced_up_to_the_window() {
+        let metrics = [metric(300, U16), metric(301, F32), metric(303, U16), metric(304, U16)];
+        let reads = planned(&metrics);
+
+        assert_eq!(coalesce(&reads, 0, 125), (4, 305));
+        assert_eq!(coalesce(&reads, 0, 4), (3, 304));
+        assert_eq!(coalesce(&reads, 0, 1), (1, 301));
+        assert_eq!(coalesce(&reads, 3, 125), (4, 305));
+    }
+
+    #[test]
+    fn gaps_and_other_spaces_end_a_read() {
+        let metrics = [metric(300, U16), metric(302, U16), metric(303, Coil)];
+        let reads = planned(&metrics);
+
+        assert_eq!(coalesce(&reads, 0, 125), (1, 301));
+        assert_eq!(coalesce(&reads, 1, 125), (2, 303));
+    }
+
+    #[test]
+    fn metrics_longer_than_the_window_are_read_whole() {
+        let metrics = [metric(300, F64), metric(304, U16)];
+        assert_eq!(coalesce(&planned(&metrics), 0, 1), (1, 304));
+    }
+
+    #[tokio::test]
+    async fn rejected_reads_halve_the_window() {
+        let mut ctx = tcp::connect_slave(serve_limited(3).await, Slave(1)).await.unwrap();
+        let metrics: Vec<MetricDef> = (300..306).map(|address| metric(address, U16)).collect();
+
+        let mut window = 8;
+        let (samples, registers_read) =
+            read_planned(&mut ctx, &planned(&metrics), &mut window, &mut RawRegisters::new()).await.unwrap();
+        // The read of all six is rejected, then they are read three at a time
+        assert_eq!(window, 3);
+        assert_eq!(registers_read, 6);
+        let values: Vec<f64> = samples.iter().map(|s| s.value.as_f64()).collect();
+        assert_eq!(values, [300.0, 301.0, 302.0, 303.0, 304.0, 305.0]);
+    }
+
+    fn group(name: &str, addresses: std::ops::Range<u16>) -> Group {
+        Group {
+            name: name.to_string(),
+            component: "_sum".to_string(),
+            metrics: addresses.map(|address| metric(address, U16)).collect(),
+            modules: None,
+            devices: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn the_window_only_shrinks_once_shorter_reads_succeed() {
+        let device = Device { host: serve_limited(3).await, unit_id: 1 };
+        let state = ModbusState { read_window: Some(8), ..Default::default() };
+        let read_window = || state.targets.lock().unwrap().get(&device).and_then(|t| t.read_window);
+        let mut connection = None;
+
+        // Rejected at every length, so the group is missing rather than the read too long
+        *state.table.write().unwrap() = Arc::new(vec![group("missing", 1000..1006), group("short", 300..303)]);
+        read_device(&state, Framing::Tcp, &mut connection, device, None).await.unwrap();
+        assert_eq!(read_window(), None);
+        assert!(!state.targets.lock().unwrap()[&device].register_groups["missing"].supported);
+
+        *state.table.write().unwrap() = Arc::new(vec![group("long", 300..306)]);
+        let (groups, _) = read_device(&state, Framing::Tcp, &mut connection, device, None).await.unwrap();
+        assert_eq!(read_window(), Some(3));
+        assert_eq!(groups[0].1.len(), 6);
+    }
+
+    #[tokio::test]
+    async fn modules_are_read_for_the_counted_towers() {
+        let mut ctx = tcp::connect_slave(serve_limited(8).await, Slave(1)).await.unwrap();
+        // The registers hold their address, so there are 2 towers of 3 modules
+        let batteries: Vec<crate::modules::BatteryModules> = serde_yaml::from_str(
+            "[{component: battery0, towers: {address: 2}, modules: {address: 3}, start: 500,
+               tower_stride: 100, module_stride: 10,
+               metrics: [{name: fems_battery_module_temperature, offset: 1, type: u16}]}]",
+        )
+        .unwrap();
+        let group = &crate::modules::module_groups(&batteries)[0];
+
+        let (samples, _) = read_group(&mut ctx, group, 0, &mut 8, &mut RawRegisters::new()).await.unwrap();
+        let modules: Vec<(&str, &str, f64)> = samples
+            .iter()
+            .map(|s| (s.labels[0].1.as_str(), s.labels[1].1.as_str(), s.value.as_f64()))
+            .collect();
+        assert_eq!(
+            modules,
+            [
+                ("0", "0", 501.0),
+                ("0", "1", 511.0),
+                ("0", "2", 521.0),
+                ("1", "0", 601.0),
+                ("1", "1", 611.0),
+                ("1", "2", 621.0),
+            ]
+        );
+    }
+
+    fn counter(value: f64, phase: &str) -> Sample {
+        Sample {
+            name: "fems_grid_buy_active_energy_watthours_total".to_string(),
+            labels: vec![("phase".to_string(), phase.to_string())],
+            value: Value::F64(value),
+        }
+    }
+
+    #[test]
+    fn counters_dropping_sharply_are_reset() {
+        let mut status = TargetStatus::default();
+        let resets = |status: &TargetStatus| status.counter_resets.values().sum::<u64>();
+
+        detect_counter_reset(&mut status, device(), &counter(1000.0, "l1"));
+        assert_eq!(resets(&status), 0);
+
+        // Within the noise of 10%
+        detect_counter_reset(&mut status, device(), &counter(910.0, "l1"));
+        assert_eq!(resets(&status), 0);
+
+        detect_counter_reset(&mut status, device(), &counter(810.0, "l1"));
+        assert_eq!(resets(&status), 1);
+
+        detect_counter_reset(&mut status, device(), &counter(900.0, "l1"));
+        assert_eq!(resets(&status), 1);
+    }
+
+    #[test]
+    fn counters_are_compared_per_series() {
+        let mut status = TargetStatus::default();
+
+        detect_counter_reset(&mut status, device(), &counter(1000.0, "l1"));
+        detect_counter_reset(&mut status, device(), &counter(10.0, "l2"));
+        assert!(status.counter_resets.is_empty());
+    }
+
+    const ADAPTIVE: AdaptiveTtl = AdaptiveTtl { threshold: Duration::from_secs(2), max_ttl: Duration::from_secs(20) };
+    const BASE: Duration = Duration::from_secs(5);
+
+    fn adapted(status: &mut TargetStatus, took: f64) -> Option<f64> {
+        adapt_cache_ttl(status, device(), ADAPTIVE, BASE, Duration::from_secs_f64(took));
+        status.adapted_cache_ttl
+    }
+
+    #[test]
+    fn slow_reads_double_the_ttl_up_to_the_max() {
+        let mut status = TargetStatus::default();
+
+        assert_eq!(adapted(&mut status, 3.0), Some(10.0));
+        assert_eq!(adapted(&mut status, 3.0), Some(20.0));
+        assert_eq!(adapted(&mut status, 3.0), Some(20.0));
+    }
+
+    #[test]
+    fn fast_reads_halve_the_ttl_back_to_the_base() {
+        let mut status = TargetStatus::default();
+        adapted(&mut status, 3.0);
+        adapted(&mut status, 3.0);
+
+        assert_eq!(adapted(&mut status, 0.5), Some(10.0));
+        assert_eq!(adapted(&mut status, 0.5), None);
+        assert_eq!(adapted(&mut status, 0.5), None);
+    }
+
+    #[test]
+    fn reads_near_the_threshold_keep_the_ttl() {
+        let mut status = TargetStatus::default();
+        adapted(&mut status, 3.0);
+
+        assert_eq!(adapted(&mut status, 1.5), Some(10.0));
+        assert_eq!(adapted(&mut status, 2.0), Some(10.0));
+    }
+
+    #[test]
+    fn disabled_caching_is_raised_from_one_second() {
+        let mut status = TargetStatus::default();
+        adapt_cache_ttl(&mut status, device(), ADAPTIVE, Duration::ZERO, Duration::from_secs(3));
+        assert_eq!(status.adapted_cache_ttl, Some(2.0));
+    }
+
+    #[test]
+    fn hosts_default_to_the_modbus_port() {
+        assert_eq!(parse_host("192.168.1.5:5020").unwrap(), "192.168.1.5:5020".parse().unwrap());
+        assert_eq!(parse_host("192.168.1.5").unwrap(), "192.168.1.5:502".parse().unwrap());
+        assert_eq!(parse_host("[fd00::5]:5020").unwrap(), "[fd00::5]:5020".parse().unwrap());
+        assert_eq!(parse_host("[fd00::5]").unwrap(), "[fd00::5]:502".parse().unwrap());
+        assert_eq!(parse_host("fd00::5").unwrap(), "[fd00::5]:502".parse().unwrap());
+        assert!(parse_host("fems.local:502").is_err());
+        assert!(parse_host("[192.168.1.5]:502").is_err());
+    }
+
+    #[tokio::test]
+    async fn partial_reads_keep_the_scrape_metadata() {
+        let state = ModbusState { simulate: true, ..Default::default() };
+
+        read_recorded(&state, device(), Some(&HashSet::new())).await.unwrap();
+        {
+            let targets = state.targets.lock().unwrap();
+            let status = &targets[&device()];
+            assert!(status.connected);
+            assert_eq!(status.last_scrape, None);
+            assert_eq!(status.scrape_duration, None);
+        }
+
+        read_recorded(&state, device(), None).await.unwrap();
+        let targets = state.targets.lock().unwrap();
+        assert!(targets[&device()].last_scrape.is_some());
+        assert!(targets[&device()].scrape_duration.is_some());
+    }
+
+    #[tokio::test]
+    async fn reads_finish_without_scrapes_waiting() {
+        // Every connection is closed right away, so reads fail once they reached the device
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let device = Device { host: listener.local_addr().unwrap(), unit_id: 1 };
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                drop(socket);
+            }
+        });
+        let state = ModbusState { modbus_timeout: Duration::from_secs(5), ..Default::default() };
+
+        let mut scrape = read_samples(&state, device).boxed();
+        assert!(poll!(&mut scrape).is_pending());
+        drop(scrape);
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !state.inflight.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("read was not finished");
+        assert_eq!(state.targets.lock().unwrap()[&device].scrape_errors, 1);
+    }
+}
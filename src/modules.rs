@@ -0,0 +1,210 @@
+//! Battery towers and their modules, exported with `tower` and `module` labels.
+//!
+//! Towers and modules are laid out at fixed strides within the battery component, so one set of
+//! module registers is read repeatedly at computed offsets. How many there are is configured or
+//! read from the device.
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use crate::registers::{deserialize_label_map, Group, MetricDef, MetricKind, ModbusType};
+
+/// Upper bounds for the counts, counts read from the device are capped to them
+pub const MAX_TOWERS: u16 = 16;
+pub const MAX_MODULES: u16 = 64;
+
+/// Number of towers or modules, either fixed or read from a U16 register of the component.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum Count {
+    Fixed(u16),
+    Register { address: u16 },
+}
+
+#[derive(Clone)]
+pub struct ModuleLayout {
+    pub towers: Count,
+    pub modules: Count,
+    /// Address of the first module of the first tower
+    pub start: u16,
+    pub tower_stride: u16,
+    pub module_stride: u16,
+}
+
+impl ModuleLayout {
+    /// Address of a metric of the given module, `None` if it's beyond the address space.
+    pub fn address(&self, tower: u16, module: u16, offset: u16) -> Option<u16> {
+        let tower_start = u32::from(tower) * u32::from(self.tower_stride);
+        let module_start = u32::from(module) * u32::from(self.module_stride);
+        let address = u32::from(self.start) + tower_start + module_start + u32::from(offset);
+        address.try_into().ok()
+    }
+}
+
+/// Battery component with per-module registers, e.g. of a FENECON Home tower.
+#[derive(Deserialize)]
+#[serde(try_from = "RawBatteryModules")]
+pub struct BatteryModules {
+    component: String,
+    layout: ModuleLayout,
+    metrics: Vec<MetricDef>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawBatteryModules {
+    /// Battery component, e.g. `battery0`; addresses are relative to its block
+    component: String,
+    /// Number of towers, a number or `{address: ...}` to read it from the device
+    #[serde(default = "one")]
+    towers: Count,
+    /// Number of modules per tower, a number or `{address: ...}` to read it from the device
+    modules: Count,
+    start: u16,
+    /// Registers between the first modules of two consecutive towers
+    #[serde(default)]
+    tower_stride: u16,
+    /// Registers between two consecutive modules of a tower
+    module_stride: u16,
+    metrics: Vec<ModuleMetric>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ModuleMetric {
+    name: String,
+    /// Address relative to the start of the module
+    offset: u16,
+    #[serde(rename = "type")]
+    modbus_type: ModbusType,
+    #[serde(default, deserialize_with = "deserialize_label_map")]
+    labels: BTreeMap<String, String>,
+}
+
+fn one() -> Count {
+    Count::Fixed(1)
+}
+
+impl TryFrom<RawBatteryModules> for BatteryModules {
+    type Error = String;
+
+    fn try_from(raw: RawBatteryModules) -> Result<Self, Self::Error> {
+        for (what, count, max) in [("towers", raw.towers, MAX_TOWERS), ("modules", raw.modules, MAX_MODULES)] {
+            if let Count::Fixed(count) = count {
+                if count == 0 || count > max {
+                    return Err(format!("{count} {what} configured, expected 1 to {max}"));
+                }
+            }
+        }
+
+        let layout = ModuleLayout {
+            towers: raw.towers,
+            modules: raw.modules,
+            start: raw.start,
+            tower_stride: raw.tower_stride,
+            module_stride: raw.module_stride,
+        };
+
+        let metrics = raw
+            .metrics
+            .into_iter()
+            .map(|metric| {
+                match metric.modbus_type {
+                    ModbusType::Bitfield => return Err(format!("module metric {} can't be a bitfield", metric.name)),
+                    ModbusType::Coil | ModbusType::DiscreteInput => {
+                        return Err(format!("module metric {} must be a register", metric.name))
+                    }
+                    _ => {}
+                }
+                if metric.labels.contains_key("tower") || metric.labels.contains_key("module") {
+                    return Err(format!("module metric {} sets the tower or module label", metric.name));
+                }
+                Ok(MetricDef {
+                    name: metric.name,
+                    labels: metric.labels.into_iter().collect(),
+                    address: metric.offset,
+                    modbus_type: metric.modbus_type,
+                    kind: MetricKind::Gauge,
+                    bits: Vec::new(),
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(BatteryModules {
+            component: raw.component,
+            layout,
+            metrics,
+        })
+    }
+}
+
+/// Builds one register group per battery, its metric addresses are offsets within a module.
+pub fn module_groups(batteries: &[BatteryModules]) -> Vec<Group> {
+    batteries
+        .iter()
+        .map(|battery| Group {
+            name: format!("modules_{}", battery.component),
+            component: battery.component.clone(),
+            metrics: battery.metrics.clone(),
+            modules: Some(battery.layout.clone()),
+            devices: None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batteries(yaml: &str) -> Result<Vec<BatteryModules>, serde_yaml::Error> {
+        serde_yaml::from_str(yaml)
+    }
+
+    #[test]
+    fn modules_are_laid_out_at_their_strides() {
+        let layout = ModuleLayout {
+            towers: Count::Fixed(2),
+            modules: Count::Fixed(3),
+            start: 1000,
+            tower_stride: 500,
+            module_stride: 100,
+        };
+
+        assert_eq!(layout.address(0, 0, 4), Some(1004));
+        assert_eq!(layout.address(1, 2, 4), Some(1704));
+        assert_eq!(layout.address(200, 0, 0), None);
+    }
+
+    #[test]
+    fn counts_are_fixed_or_read_from_a_register() {
+        let batteries = batteries(
+            "[{component: battery0, towers: {address: 10}, modules: 8, start: 100, module_stride: 20,
+               metrics: [{name: fems_battery_module_voltage_volts, offset: 0, type: f32}]}]",
+        )
+        .unwrap();
+        let groups = module_groups(&batteries);
+
+        assert_eq!(groups[0].name, "modules_battery0");
+        let layout = groups[0].modules.as_ref().unwrap();
+        assert!(matches!(layout.towers, Count::Register { address: 10 }));
+        assert!(matches!(layout.modules, Count::Fixed(8)));
+    }
+
+    #[test]
+    fn invalid_batteries_are_rejected() {
+        let battery = |modules: &str, metric: &str| {
+            batteries(&format!(
+                "[{{component: battery0, modules: {modules}, start: 0, module_stride: 1, metrics: [{metric}]}}]"
+            ))
+        };
+        let metric = "{name: fems_battery_module_voltage_volts, offset: 0, type: f32}";
+
+        assert!(battery("8", metric).is_ok());
+        assert!(battery("0", metric).is_err());
+        assert!(battery("65", metric).is_err());
+        assert!(battery("8", "{name: fems_x, offset: 0, type: bitfield}").is_err());
+        assert!(battery("8", "{name: fems_x, offset: 0, type: coil}").is_err());
+        assert!(battery("8", "{name: fems_x, offset: 0, type: u16, labels: {tower: '1'}}").is_err());
+    }
+}
@@ -0,0 +1,158 @@
+//! Locates components in the process image of the OpenEMS Modbus/TCP API.
+//!
+//! The API starts with a meta block, followed by one block per component in the configured order.
+//! Every block starts with the hash of its id and its length in registers, so base addresses of
+//! components shift whenever components are added or removed.
+
+use std::{collections::BTreeMap, io};
+
+use tokio_modbus::{client::Context, prelude::*};
+
+use crate::error::Exception;
+
+/// Base address of every located component, keyed by component id.
+pub type ComponentMap = BTreeMap<String, u16>;
+
+/// Upper bound of components to walk, protects against garbage lengths.
+const MAX_COMPONENTS: usize = 256;
+
+/// Ids of components that are commonly found on a FEMS, located even if no metric uses them.
+pub const WELL_KNOWN_COMPONENTS: [&str; 9] = [
+    "_sum",
+    "ess0",
+    "meter0",
+    "meter1",
+    "charger0",
+    "charger1",
+    "battery0",
+    "batteryInverter0",
+    "pvInverter0",
+];
+
+/// Hash OpenEMS writes in front of each block, Java's `String.hashCode()` truncated to 16 bits.
+fn hash(text: &str) -> u16 {
+    let hash = text
+        .encode_utf16()
+        .fold(0i32, |hash, c| hash.wrapping_mul(31).wrapping_add(c.into()));
+    hash as u16
+}
+
+/// Walks the component blocks and returns the base addresses of the `candidates` found.
+///
+/// Returns `None` if the device doesn't start with an OpenEMS meta block.
+pub async fn scan(ctx: &mut Context, candidates: &[&str]) -> io::Result<Option<ComponentMap>> {
+    let [meta_hash, first] = read_block_header(ctx, 0).await?;
+    if meta_hash != hash("OpenEMS") {
+        return Ok(None);
+    }
+
+    let candidates: Vec<(u16, &str)> = candidates.iter().map(|id| (hash(id), *id)).collect();
+    let mut components = ComponentMap::new();
+    let mut address = first;
+
+    for _ in 0..MAX_COMPONENTS {
+        let [id_hash, length] = match read_block_header(ctx, address).await {
+            Ok(block) => block,
+            // Reading past the last block is answered with an illegal data address
+            Err(e) if Exception::from_io(&e).is_some_and(|e| e.is_permanent()) => break,
+            Err(e) => return Err(e),
+        };
+        if length == 0 {
+            break;
+        }
+
+        if let Some((_, id)) = candidates.iter().find(|(hash, _)| *hash == id_hash) {
+            components.insert(id.to_string(), address);
+        }
+
+        let Some(next) = address.checked_add(length) else {
+            break;
+        };
+        address = next;
+    }
+
+    Ok(Some(components))
+}
+
+/// Hash and length the block at `address` starts with.
+async fn read_block_header(ctx: &mut Context, address: u16) -> io::Result<[u16; 2]> {
+    let data = ctx.read_input_registers(address, 2).await?;
+    data.as_slice().try_into().map_err(|_| {
+        let message = format!("expected 2 registers at {address}, got {}", data.len());
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tokio_modbus::client::tcp;
+
+    use super::*;
+
+    /// Serves `image` as input registers over Modbus/TCP, reads past its end are answered with
+    /// the exception `past_end`.
+    async fn serve_image(image: Vec<u16>, past_end: u8) -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let host = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 12];
+            while socket.read_exact(&mut request).await.is_ok() {
+                let start = usize::from(u16::from_be_bytes([request[8], request[9]]));
+                let count = usize::from(u16::from_be_bytes([request[10], request[11]]));
+                let pdu = match image.get(start..start + count) {
+                    Some(registers) => {
+                        let mut pdu = vec![0x04, (count * 2) as u8];
+                        registers.iter().for_each(|register| pdu.extend_from_slice(&register.to_be_bytes()));
+                        pdu
+                    }
+                    None => vec![0x84, past_end],
+                };
+
+                let mut response = request[..4].to_vec();
+                response.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+                response.push(request[6]);
+                response.extend_from_slice(&pdu);
+                socket.write_all(&response).await.unwrap();
+            }
+        });
+
+        host
+    }
+
+    /// Meta block followed by `_sum` with three registers and `ess0` with two.
+    fn image() -> Vec<u16> {
+        vec![hash("OpenEMS"), 2, hash("_sum"), 3, 0, hash("ess0"), 2]
+    }
+
+    #[tokio::test]
+    async fn components_are_located_up_to_the_end_of_the_table() {
+        let mut ctx = tcp::connect_slave(serve_image(image(), 0x02).await, Slave(1)).await.unwrap();
+
+        let components = scan(&mut ctx, &["_sum", "ess0", "meter0"]).await.unwrap().unwrap();
+        assert_eq!(components, ComponentMap::from([("_sum".to_string(), 2), ("ess0".to_string(), 5)]));
+    }
+
+    #[tokio::test]
+    async fn other_exceptions_fail_the_scan() {
+        // Server device failure, the device may well have more components
+        let mut ctx = tcp::connect_slave(serve_image(image(), 0x04).await, Slave(1)).await.unwrap();
+
+        let error = scan(&mut ctx, &["_sum"]).await.unwrap_err();
+        assert_eq!(Exception::from_io(&error).map(|e| e.code), Some(0x04));
+    }
+
+    #[tokio::test]
+    async fn devices_without_a_meta_block_are_not_scanned() {
+        let mut ctx = tcp::connect_slave(serve_image(vec![1, 2], 0x02).await, Slave(1)).await.unwrap();
+
+        assert_eq!(scan(&mut ctx, &["_sum"]).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,190 @@
+use std::{error::Error, time::Duration};
+
+use axum::http::HeaderMap;
+use opentelemetry::{
+    metrics::{MeterProvider as _, Unit},
+    propagation::{Extractor, TextMapPropagator},
+    trace::{Span, SpanKind, Status, Tracer as _},
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    metrics::MeterProvider,
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{self, Tracer},
+    Resource,
+};
+
+use crate::{
+    config::OtlpConfig,
+    modbus::metric_names,
+    poller::Snapshot,
+    registers::{Group, MetricKind},
+};
+
+/// Starts pushing the metrics found in `snapshot` to an OpenTelemetry collector.
+///
+/// The returned provider has to be shut down to flush the last export.
+pub fn start(
+    config: &OtlpConfig,
+    table: &[Group],
+    snapshot: Snapshot,
+) -> Result<MeterProvider, Box<dyn Error>> {
+    let provider = opentelemetry_otlp::new_pipeline()
+        .metrics(runtime::Tokio)
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(&config.endpoint),
+        )
+        .with_period(Duration::from_secs(config.interval))
+        .with_resource(resource(config))
+        .build()?;
+
+    let meter = provider.meter(env!("CARGO_PKG_NAME"));
+
+    for (name, kind) in metric_names(table) {
+        let unit = unit(&name);
+        let snapshot = snapshot.clone();
+        let observed_name = name.clone();
+        let observe = move |observe: &dyn Fn(f64, &[KeyValue])| {
+            let snapshot = snapshot.lock().unwrap();
+
+            for (fems_id, samples) in snapshot.iter() {
+                for sample in samples.iter().filter(|s| s.name == observed_name) {
+                    let mut attributes: Vec<KeyValue> = sample
+                        .labels
+                        .iter()
+                        .map(|(l, v)| KeyValue::new(l.clone(), v.clone()))
+                        .collect();
+                    attributes.push(KeyValue::new("fems_id", fems_id.clone()));
+
+                    observe(sample.value.as_f64(), &attributes);
+                }
+            }
+        };
+
+        // Instruments stay registered with the meter, so they don't need to be kept around
+        match kind {
+            MetricKind::Gauge => {
+                let mut builder = meter
+                    .f64_observable_gauge(name)
+                    .with_callback(move |i| observe(&|v, a| i.observe(v, a)));
+                if let Some(unit) = unit {
+                    builder = builder.with_unit(unit);
+                }
+                builder.try_init()?;
+            }
+            MetricKind::Counter => {
+                let mut builder = meter
+                    .f64_observable_counter(name)
+                    .with_callback(move |i| observe(&|v, a| i.observe(v, a)));
+                if let Some(unit) = unit {
+                    builder = builder.with_unit(unit);
+                }
+                builder.try_init()?;
+            }
+        }
+    }
+
+    Ok(provider)
+}
+
+fn resource(config: &OtlpConfig) -> Resource {
+    let mut attributes = vec![
+        KeyValue::new("service.name", env!("CARGO_PKG_NAME")),
+        KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+    ];
+    attributes.extend(
+        config
+            .resource
+            .iter()
+            .map(|(k, v)| KeyValue::new(k.clone(), v.clone())),
+    );
+    Resource::new(attributes)
+}
+
+/// Starts exporting a span per scrape of /metrics to the collector.
+///
+/// Spans are exported in batches, [`opentelemetry::global::shutdown_tracer_provider`] flushes the last one.
+pub fn start_tracing(config: &OtlpConfig) -> Result<Tracer, Box<dyn Error>> {
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(&config.endpoint),
+        )
+        .with_trace_config(trace::config().with_resource(resource(config)))
+        .install_batch(runtime::Tokio)?;
+
+    Ok(tracer)
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+/// Span of a scrape, continuing the trace of the scraper if it sent a `traceparent` header.
+pub struct ScrapeSpan {
+    span: trace::Span,
+}
+
+impl ScrapeSpan {
+    pub fn start(tracer: &Tracer, headers: &HeaderMap, fems_id: &str) -> Self {
+        let parent = TraceContextPropagator::new().extract(&HeaderExtractor(headers));
+        let span = tracer
+            .span_builder("scrape")
+            .with_kind(SpanKind::Server)
+            .with_attributes(vec![KeyValue::new("fems_id", fems_id.to_string())])
+            .start_with_context(tracer, &parent);
+
+        ScrapeSpan { span }
+    }
+
+    pub fn trace_id(&self) -> String {
+        self.span.span_context().trace_id().to_string()
+    }
+
+    /// Ends the span, recording `error` as its status if the scrape failed.
+    pub fn end(mut self, error: Option<&str>) {
+        if let Some(error) = error {
+            self.span.set_status(Status::error(error.to_string()));
+        }
+        self.span.end();
+    }
+}
+
+/// Derives the UCUM unit from the metric name suffix.
+fn unit(name: &str) -> Option<Unit> {
+    let name = name.trim_end_matches("_total");
+
+    let unit = if name.ends_with("_watthours") {
+        "Wh"
+    } else if name.ends_with("_watts") {
+        "W"
+    } else if name.ends_with("_voltampere") {
+        "VA"
+    } else if name.ends_with("_percent") {
+        "%"
+    } else if name.ends_with("_volts") {
+        "V"
+    } else if name.ends_with("_amperes") {
+        "A"
+    } else if name.ends_with("_celsius") {
+        "Cel"
+    } else {
+        return None;
+    };
+
+    Some(Unit::new(unit))
+}
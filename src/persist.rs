@@ -0,0 +1,115 @@
+//! Keeps the last known good values across restarts, so dashboards don't show gaps while the
+//! exporter reconnects.
+
+use std::{error::Error, fs, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::modbus::{CacheEntry, Device, ModbusState, Sample};
+
+#[derive(Serialize, Deserialize)]
+struct StoredDevice {
+    #[serde(flatten)]
+    device: Device,
+    samples: Vec<Sample>,
+}
+
+/// Restores the samples saved by [`save`], they are served as stale until a fresh read succeeds.
+///
+/// A state file that can't be read only costs the values of the last run, so the exporter starts
+/// without them instead of refusing to start.
+pub fn load(path: &Path, state: &ModbusState) {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        // Nothing has been saved yet
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => {
+            warn!("unable to read state file {}, starting without saved values: {e}", path.display());
+            return;
+        }
+    };
+
+    let stored: Vec<StoredDevice> = match serde_json::from_str(&content) {
+        Ok(stored) => stored,
+        Err(e) => {
+            warn!("invalid state file {}, starting without saved values: {e}", path.display());
+            return;
+        }
+    };
+
+    let mut cache = state.cache.lock().unwrap();
+    for StoredDevice { device, samples } in stored {
+        cache.insert(device, CacheEntry { read_at: None, samples });
+    }
+}
+
+/// Writes the last successfully read samples of every device to `path`.
+pub fn save(path: &Path, state: &ModbusState) -> Result<(), Box<dyn Error>> {
+    let stored: Vec<StoredDevice> = state
+        .cache
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(device, entry)| StoredDevice {
+            device: *device,
+            // JSON has no NaN or infinity, serde_json would write them as null which can't be read back
+            samples: entry.samples.iter().filter(|s| s.value.as_f64().is_finite()).cloned().collect(),
+        })
+        .collect();
+
+    // Write to a temporary file first, so a crash can't leave a truncated state file behind
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, serde_json::to_string(&stored)?)
+        .and_then(|()| fs::rename(&temporary, path))
+        .map_err(|e| format!("unable to write state file {}: {e}", path.display()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::modbus::Value;
+
+    fn sample(name: &str, value: Value) -> Sample {
+        Sample { name: name.to_string(), labels: Vec::new(), value }
+    }
+
+    #[test]
+    fn saved_values_are_restored_without_the_non_finite_ones() {
+        let path = std::env::temp_dir().join(format!("fems_exporter_persist_{}.json", std::process::id()));
+        let device = Device { host: "127.0.0.1:502".parse().unwrap(), unit_id: 1 };
+        let state = ModbusState::default();
+        let samples = vec![
+            sample("power", Value::F32(230.5)),
+            sample("missing", Value::F32(f32::NAN)),
+            sample("overflow", Value::F64(f64::INFINITY)),
+            sample("state", Value::U16(2)),
+        ];
+        state.cache.lock().unwrap().insert(device, CacheEntry { read_at: None, samples });
+        save(&path, &state).unwrap();
+
+        let restored = ModbusState::default();
+        load(&path, &restored);
+        fs::remove_file(&path).unwrap();
+
+        let cache = restored.cache.lock().unwrap();
+        let names: Vec<_> = cache[&device].samples.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["power", "state"]);
+        assert!(cache[&device].read_at.is_none());
+    }
+
+    #[test]
+    fn an_invalid_state_file_starts_empty() {
+        let path = std::env::temp_dir().join(format!("fems_exporter_invalid_{}.json", std::process::id()));
+        fs::write(&path, r#"[{"host":"127.0.0.1:502","unit_id":1,"samples":[{"value":{"F32":null}}]}]"#).unwrap();
+
+        let state = ModbusState::default();
+        load(&path, &state);
+        fs::remove_file(&path).unwrap();
+
+        assert!(state.cache.lock().unwrap().is_empty());
+    }
+}
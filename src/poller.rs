@@ -0,0 +1,105 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::warn;
+
+use crate::{
+    config::{OtlpConfig, Target},
+    modbus::{read_groups, ModbusState, Sample},
+    predictions,
+};
+
+/// Latest samples of every successfully polled target, keyed by fems_id.
+pub type Snapshot = Arc<Mutex<HashMap<String, Vec<Sample>>>>;
+
+/// Polls all `targets` in the background, every register group at its configured interval.
+///
+/// The poller ticks at the greatest common divisor of all intervals, each tick reads the groups
+/// whose interval is a multiple of the ticks passed. Reads bypass the cache of /metrics. The
+/// groups are taken from the metric table on every tick, so a reload changes what is polled.
+pub fn spawn(state: ModbusState, targets: Vec<Target>, config: &OtlpConfig) -> Snapshot {
+    let snapshot = Snapshot::default();
+    let shared = snapshot.clone();
+
+    let default = config.interval.max(1);
+    let group_intervals = config.group_intervals.clone();
+    let tick = group_intervals
+        .values()
+        .fold(default, |tick, interval| gcd(tick, (*interval).max(1)));
+
+    tokio::spawn(async move {
+        let mut ticker = interval(Duration::from_secs(tick));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        // Last read samples of every group, by fems_id and group name
+        let mut groups: HashMap<String, BTreeMap<String, Vec<Sample>>> = HashMap::new();
+
+        for count in 0u64.. {
+            ticker.tick().await;
+
+            // Number of ticks between reads of each group
+            let ticks: Vec<(String, u64)> = state
+                .table()
+                .iter()
+                .map(|group| {
+                    let interval = group_intervals.get(&group.name).copied().unwrap_or(default);
+                    (group.name.clone(), interval.max(1) / tick)
+                })
+                .collect();
+
+            for target in &targets {
+                // Slow devices keep their last snapshot until their raised cache TTL expired
+                if state.throttled(target.device()) {
+                    continue;
+                }
+                let read = groups.entry(target.fems_id.clone()).or_default();
+                // Groups removed by a reload are no longer exported
+                read.retain(|group, _| ticks.iter().any(|(name, _)| name == group));
+
+                // Groups never read successfully are retried on every tick
+                let due: HashSet<String> = ticks
+                    .iter()
+                    .filter(|(group, every)| count % every == 0 || !read.contains_key(group))
+                    .map(|(group, _)| group.clone())
+                    .collect();
+
+                let result = read_groups(&state, target.device(), &due).await;
+                let predictions = match result {
+                    Ok(_) => predictions::read(&state, target.device()).await,
+                    Err(_) => Vec::new(),
+                };
+
+                let mut snapshot = shared.lock().unwrap();
+                match result {
+                    Ok(samples) => {
+                        read.extend(samples);
+                        let samples = read.values().flatten().cloned().collect();
+                        let mut samples = state.with_derived(samples);
+                        samples.extend(predictions);
+                        snapshot.insert(target.fems_id.clone(), samples);
+                    }
+                    Err(e) => {
+                        warn!(fems_id = target.fems_id, kind = e.kind(), "polling failed: {e}");
+                        // Don't keep exporting outdated values
+                        read.clear();
+                        snapshot.remove(&target.fems_id);
+                    }
+                }
+            }
+        }
+    });
+
+    snapshot
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
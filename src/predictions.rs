@@ -0,0 +1,198 @@
+//! Production and consumption forecasts of the OpenEMS predictor manager.
+//!
+//! Predictions are only available via JSON-RPC, so they are only read from configured targets
+//! with `predictions: true`. They are exported as one series per quarter hour of the next 24 hours,
+//! labeled with `horizon_minutes`.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value as Json};
+use tokio::{sync::Mutex, time::timeout};
+use tracing::warn;
+
+use crate::{
+    error::ReadError,
+    jsonrpc::JsonRpcClient,
+    modbus::{Device, ModbusState, Sample, Value},
+};
+
+/// Predicted channels and the metrics they are exported as
+pub const PREDICTION_METRICS: [(&str, &str); 2] = [
+    ("_sum/ProductionActivePower", "fems_production_forecast_watts"),
+    ("_sum/ConsumptionActivePower", "fems_consumption_forecast_watts"),
+];
+
+/// Minutes between two predicted values
+const STEP_MINUTES: usize = 15;
+
+/// Predictions only change every quarter hour, asking once a minute keeps the horizons current.
+const REFRESH: Duration = Duration::from_secs(60);
+
+/// Predictor manager of a configured target, with its last predictions.
+pub struct Predictor {
+    client: JsonRpcClient,
+    last: Mutex<Option<(Instant, Vec<Sample>)>>,
+}
+
+/// Predictors of the configured targets that have predictions enabled.
+pub type Predictors = HashMap<Device, Predictor>;
+
+impl Predictor {
+    pub fn new(client: JsonRpcClient) -> Self {
+        Predictor {
+            client,
+            last: Mutex::new(None),
+        }
+    }
+
+    async fn samples(&self) -> Result<Vec<Sample>, ReadError> {
+        let mut last = self.last.lock().await;
+        if let Some((read_at, samples)) = &*last {
+            if read_at.elapsed() < REFRESH {
+                return Ok(samples.clone());
+            }
+        }
+
+        let channels: Vec<&str> = PREDICTION_METRICS.iter().map(|(channel, _)| *channel).collect();
+        let result = self
+            .client
+            .component_request("_predictorManager", "get24HoursPrediction", json!({"channels": channels}))
+            .await?;
+
+        let mut samples = Vec::new();
+        for (channel, metric) in PREDICTION_METRICS {
+            let Some(values) = result.get(channel).and_then(Json::as_array) else {
+                return Err(ReadError::Decode { message: format!("prediction of {channel} missing") });
+            };
+
+            // Quarter hours without a prediction are null
+            for (i, value) in values.iter().enumerate() {
+                let Some(value) = value.as_f64() else {
+                    continue;
+                };
+                samples.push(Sample {
+                    name: metric.to_string(),
+                    labels: vec![("horizon_minutes".to_string(), (i * STEP_MINUTES).to_string())],
+                    value: Value::F64(value),
+                });
+            }
+        }
+
+        *last = Some((Instant::now(), samples.clone()));
+        Ok(samples)
+    }
+}
+
+/// Predictions for `device`, empty if it has none configured or they can't be read.
+///
+/// Predictions are an addition to the Modbus values, failing to read them doesn't fail the scrape.
+pub async fn read(state: &ModbusState, device: Device) -> Vec<Sample> {
+    let Some(predictor) = state.predictors.get(&device) else {
+        return Vec::new();
+    };
+
+    let result = timeout(state.modbus_timeout, predictor.samples())
+        .await
+        .unwrap_or_else(|_| Err(ReadError::timeout(state.modbus_timeout)));
+
+    result.unwrap_or_else(|e| {
+        state.internal.count_read_error(device.host, &e);
+        warn!(host = %device.host, unit_id = device.unit_id, kind = e.kind(), "unable to read predictions: {e}");
+        Vec::new()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr, TcpListener},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use axum::{routing::post, Json as JsonBody, Router};
+
+    use super::*;
+
+    /// Serves the JSON-RPC API, answering prediction requests with `result` and counting them.
+    fn serve_predictions(result: Json, requests: Arc<AtomicUsize>) -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let host = listener.local_addr().unwrap();
+
+        let app = Router::new().route(
+            "/jsonrpc",
+            post(move |JsonBody(request): JsonBody<Json>| {
+                let result = result.clone();
+                requests.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    assert_eq!(request["params"]["componentId"], "_predictorManager");
+                    assert_eq!(request["params"]["payload"]["method"], "get24HoursPrediction");
+                    JsonBody(json!({"jsonrpc": "2.0", "id": request["id"], "result": result}))
+                }
+            }),
+        );
+        listener.set_nonblocking(true).unwrap();
+        let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        host
+    }
+
+    fn state(result: Json, requests: Arc<AtomicUsize>) -> (ModbusState, Device) {
+        let host = serve_predictions(result, requests);
+        let device = Device { host, unit_id: 1 };
+        let client = JsonRpcClient::new(Some(&format!("http://{host}/jsonrpc")), host.ip(), None).unwrap();
+        let state = ModbusState {
+            predictors: Arc::new(Predictors::from([(device, Predictor::new(client))])),
+            modbus_timeout: Duration::from_secs(5),
+            ..Default::default()
+        };
+        (state, device)
+    }
+
+    fn horizons(samples: &[Sample]) -> Vec<(&str, &str, f64)> {
+        samples
+            .iter()
+            .map(|s| (s.name.as_str(), s.labels[0].1.as_str(), s.value.as_f64()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn predictions_are_labeled_with_their_horizon() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let result = json!({
+            "_sum/ProductionActivePower": [1200, null, 800],
+            "_sum/ConsumptionActivePower": [400],
+        });
+        let (state, device) = state(result, requests.clone());
+
+        let samples = read(&state, device).await;
+        assert_eq!(
+            horizons(&samples),
+            [
+                ("fems_production_forecast_watts", "0", 1200.0),
+                ("fems_production_forecast_watts", "30", 800.0),
+                ("fems_consumption_forecast_watts", "0", 400.0),
+            ]
+        );
+
+        // Only asked again once the predictions are a minute old
+        assert_eq!(read(&state, device).await.len(), 3);
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn missing_predictions_are_left_out() {
+        let (state, device) = state(json!({"_sum/ProductionActivePower": [1200]}), Default::default());
+
+        assert!(read(&state, device).await.is_empty());
+        // Targets without predictions enabled
+        let other = Device { host: "127.0.0.1:502".parse().unwrap(), unit_id: 1 };
+        assert!(read(&state, other).await.is_empty());
+    }
+}
@@ -0,0 +1,103 @@
+//! Pushes the metrics of the configured targets to a Prometheus Pushgateway.
+
+use std::time::Duration;
+
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{Method, Request, Uri};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::warn;
+
+use crate::{
+    config::Target,
+    downsample,
+    exposition,
+    modbus::{read_samples, ModbusState},
+};
+
+const JOB: &str = "fems";
+
+/// Pushgateway whose URL was checked, see [`check`].
+pub struct Pushgateway {
+    /// URL without a trailing slash
+    base: String,
+}
+
+/// Checks that `url` can be pushed the metrics of `targets` to.
+pub fn check(url: &str, targets: &[Target]) -> Result<Pushgateway, String> {
+    let base: Uri = url.parse().map_err(|e| format!("invalid Pushgateway URL {url}: {e}"))?;
+    if base.scheme_str() != Some("http") {
+        return Err(format!("invalid Pushgateway URL {url}: only http:// is supported"));
+    }
+    if targets.is_empty() {
+        return Err("pushing to a Pushgateway requires targets in the config".to_string());
+    }
+    Ok(Pushgateway { base: url.trim_end_matches('/').to_string() })
+}
+
+/// Pushes all `targets` every `period`, grouped by `job="fems"` and `instance=<fems_id>`.
+///
+/// The group of a target that can't be read is deleted, so the Pushgateway doesn't keep
+/// serving outdated values. Only plain HTTP is supported.
+pub fn spawn(state: ModbusState, targets: Vec<Target>, pushgateway: Pushgateway, period: Duration) {
+    let Pushgateway { base } = pushgateway;
+    let client: Client<HttpConnector, Full<Bytes>> = Client::builder(TokioExecutor::new()).build_http();
+
+    tokio::spawn(async move {
+        let mut ticker = interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            ticker.tick().await;
+
+            for target in &targets {
+                let uri = format!("{base}/metrics/job/{JOB}/{}", instance(&target.fems_id));
+                let device = target.device();
+
+                let request = match read_samples(&state, device).await {
+                    Ok(mut samples) => {
+                        samples.extend(state.scrape_samples(device));
+                        samples.extend(downsample::drain(&state.windows, device));
+                        let body = exposition::render(&state.series(&samples, &target.fems_id), state.number_format);
+                        Request::builder().method(Method::PUT).uri(&uri).body(Full::from(body))
+                    }
+                    Err(e) => {
+                        warn!(fems_id = target.fems_id, kind = e.kind(), "polling failed, deleting pushed metrics: {e}");
+                        Request::builder().method(Method::DELETE).uri(&uri).body(Full::default())
+                    }
+                };
+
+                let result = match request {
+                    Ok(request) => client.request(request).await.map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                };
+                match result {
+                    Ok(response) if !response.status().is_success() => {
+                        warn!(fems_id = target.fems_id, "Pushgateway responded with {}", response.status());
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!(fems_id = target.fems_id, "unable to push to {uri}: {e}"),
+                }
+            }
+        }
+    });
+}
+
+/// Grouping key segment for `fems_id`, base64 encoded if it isn't safe to use in a path.
+fn instance(fems_id: &str) -> String {
+    let safe = !fems_id.is_empty()
+        && fems_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+
+    if safe {
+        format!("instance/{fems_id}")
+    } else {
+        format!("instance@base64/{}", URL_SAFE.encode(fems_id))
+    }
+}
@@ -0,0 +1,440 @@
+//! Registers of the FEMS Modbus/TCP API that are exported as metrics.
+
+use std::collections::BTreeMap;
+
+use serde::{de, Deserialize, Deserializer};
+
+use crate::{
+    config::Config,
+    custom::custom_groups,
+    faults::fault_groups,
+    io::io_group,
+    modbus::Device,
+    modules::{module_groups, ModuleLayout},
+};
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModbusType {
+    U16,
+    F32,
+    F64,
+    /// 16 independent flags, see [`MetricDef::bits`]
+    Bitfield,
+    /// A relay, read from the coils
+    Coil,
+    /// A digital input, read from the discrete inputs
+    #[serde(rename = "discrete_input")]
+    DiscreteInput,
+}
+
+pub use ModbusType::{Bitfield, Coil, DiscreteInput, U16, F32, F64};
+
+/// Address spaces of Modbus, each is read with its own function.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Space {
+    InputRegisters,
+    Coils,
+    DiscreteInputs,
+}
+
+impl ModbusType {
+    /// Number of registers, or bits for coils and discrete inputs.
+    pub fn register_count(&self) -> u16 {
+        match self {
+            U16 | Bitfield | Coil | DiscreteInput => 1,
+            F32 => 2,
+            F64 => 4,
+        }
+    }
+
+    pub fn space(&self) -> Space {
+        match self {
+            U16 | F32 | F64 | Bitfield => Space::InputRegisters,
+            Coil => Space::Coils,
+            DiscreteInput => Space::DiscreteInputs,
+        }
+    }
+}
+
+pub type Labels = &'static [(&'static str, &'static str)];
+
+/// Checks that `name` is a valid Prometheus label name that isn't reserved.
+pub fn check_label_name(name: &str) -> Result<(), String> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    if !valid {
+        Err(format!("invalid label name {name:?}"))
+    } else if name.starts_with("__") {
+        Err(format!("label name {name:?} is reserved"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Checks that `name` is a valid Prometheus metric name.
+pub fn check_metric_name(name: &str) -> Result<(), String> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':');
+
+    match valid {
+        true => Ok(()),
+        false => Err(format!("invalid metric name {name:?}")),
+    }
+}
+
+/// Deserializes a map whose keys are label names.
+pub fn deserialize_label_map<'de, D, M>(deserializer: D) -> Result<M, D::Error>
+where
+    D: Deserializer<'de>,
+    M: Deserialize<'de>,
+    for<'a> &'a M: IntoIterator<Item = (&'a String, &'a String)>,
+{
+    let map = M::deserialize(deserializer)?;
+    for (label, _) in &map {
+        check_label_name(label).map_err(de::Error::custom)?;
+    }
+    Ok(map)
+}
+
+/// Deserializes a map from label names to the names they are renamed to.
+pub fn deserialize_label_renames<'de, D, M>(deserializer: D) -> Result<M, D::Error>
+where
+    D: Deserializer<'de>,
+    M: Deserialize<'de>,
+    for<'a> &'a M: IntoIterator<Item = (&'a String, &'a String)>,
+{
+    let map = M::deserialize(deserializer)?;
+    for (from, to) in &map {
+        check_label_name(from)
+            .and_then(|_| check_label_name(to))
+            .map_err(de::Error::custom)?;
+    }
+    Ok(map)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricKind {
+    Gauge,
+    Counter,
+}
+
+/// Built-in metric definition, see [`MetricDef`] for the one that is actually read.
+pub struct Metric {
+    pub name: &'static str,
+    pub labels: Labels,
+    pub address: u16,
+    pub modbus_type: ModbusType,
+    pub kind: MetricKind,
+}
+
+const fn gauge(name: &'static str, labels: Labels, address: u16, modbus_type: ModbusType) -> Metric {
+    Metric { name, labels, address, modbus_type, kind: MetricKind::Gauge }
+}
+
+const fn counter(name: &'static str, labels: Labels, address: u16, modbus_type: ModbusType) -> Metric {
+    Metric { name, labels, address, modbus_type, kind: MetricKind::Counter }
+}
+
+/// Registers that are read and checked for availability together.
+///
+/// All registers of a group belong to one OpenEMS component, their addresses assume the component
+/// block starts at its [`default_address`] or at 0 if there is none.
+pub struct RegisterGroup {
+    pub name: &'static str,
+    pub component: &'static str,
+    pub metrics: &'static [Metric],
+}
+
+/// Component of the coils and discrete inputs, which are addressed absolutely.
+pub const IO_COMPONENT: &str = "_io";
+
+/// Address a component starts at if it can't be located in the OpenEMS component table.
+pub fn default_address(component: &str) -> Option<u16> {
+    match component {
+        // Always the first component after the 200 registers of the meta block
+        "_sum" => Some(200),
+        IO_COMPONENT => Some(0),
+        _ => None,
+    }
+}
+
+pub const REGISTER_GROUPS: [RegisterGroup; 9] = [
+    RegisterGroup {
+        name: "state",
+        component: "_sum",
+        metrics: &[
+            gauge("fems_state", &[], 222, U16),
+        ],
+    },
+    RegisterGroup {
+        name: "ess",
+        component: "_sum",
+        metrics: &[
+            gauge("fems_ess_soc_percent", &[], 302, U16),
+            gauge("fems_ess_power_watts_total", &[], 303, F32),
+            gauge("fems_ess_power_watts", &[("phase", "l1")], 391, F32),
+            gauge("fems_ess_power_watts", &[("phase", "l2")], 393, F32),
+            gauge("fems_ess_power_watts", &[("phase", "l3")], 395, F32),
+            gauge("fems_ess_discharge_power_watts_total", &[], 415, F32),
+            gauge("fems_ess_reactive_power_voltampere", &[], 309, F32),
+        ],
+    },
+    RegisterGroup {
+        name: "ess_capacity",
+        component: "_sum",
+        metrics: &[
+            gauge("fems_ess_capacity_watthours", &[], 418, F32),
+        ],
+    },
+    RegisterGroup {
+        name: "grid",
+        component: "_sum",
+        metrics: &[
+            gauge("fems_grid_mode", &[], 417, U16),
+            gauge("fems_grid_power_watts_total", &[], 315, F32),
+            gauge("fems_grid_power_watts", &[("phase", "l1")], 397, F32),
+            gauge("fems_grid_power_watts", &[("phase", "l2")], 399, F32),
+            gauge("fems_grid_power_watts", &[("phase", "l3")], 401, F32),
+        ],
+    },
+    RegisterGroup {
+        name: "production",
+        component: "_sum",
+        metrics: &[
+            gauge("fems_production_power_watts_total", &[], 327, F32),
+            gauge("fems_production_power_watts", &[("type", "dc")], 339, F32),
+            gauge("fems_production_power_watts", &[("type", "ac"), ("phase", "l1")], 403, F32),
+            gauge("fems_production_power_watts", &[("type", "ac"), ("phase", "l2")], 405, F32),
+            gauge("fems_production_power_watts", &[("type", "ac"), ("phase", "l3")], 407, F32),
+        ],
+    },
+    RegisterGroup {
+        name: "consumption",
+        component: "_sum",
+        metrics: &[
+            gauge("fems_consumption_power_watts_total", &[], 343, F32),
+            gauge("fems_consumption_power_watts", &[("phase", "l1")], 409, F32),
+            gauge("fems_consumption_power_watts", &[("phase", "l2")], 411, F32),
+            gauge("fems_consumption_power_watts", &[("phase", "l3")], 413, F32),
+        ],
+    },
+    RegisterGroup {
+        name: "energy",
+        component: "_sum",
+        metrics: &[
+            counter("fems_ess_charge_energy_watthours", &[], 351, F64),
+            counter("fems_ess_discharge_energy_watthours", &[], 355, F64),
+            counter("fems_ess_dc_charge_energy_watthours", &[], 383, F64),
+            counter("fems_ess_dc_discharge_energy_watthours", &[], 387, F64),
+            counter("fems_grid_buy_energy_watthours", &[], 359, F64),
+            counter("fems_grid_sell_energy_watthours", &[], 363, F64),
+            counter("fems_production_energy_watthours_total", &[], 367, F64),
+            counter("fems_production_energy_watthours", &[("type", "ac")], 371, F64),
+            counter("fems_production_energy_watthours", &[("type", "dc")], 375, F64),
+            counter("fems_consumption_energy_watthours", &[], 379, F64),
+        ],
+    },
+    // Per-phase current and power factor of the consumption meter, only read if there is one
+    RegisterGroup {
+        name: "consumption_phases",
+        component: "meter1",
+        metrics: &[
+            gauge("fems_consumption_current_amperes", &[("phase", "l1")], 2, F32),
+            gauge("fems_consumption_current_amperes", &[("phase", "l2")], 4, F32),
+            gauge("fems_consumption_current_amperes", &[("phase", "l3")], 6, F32),
+            gauge("fems_consumption_power_factor", &[("phase", "l1")], 8, F32),
+            gauge("fems_consumption_power_factor", &[("phase", "l2")], 10, F32),
+            gauge("fems_consumption_power_factor", &[("phase", "l3")], 12, F32),
+        ],
+    },
+    // Thermal diagnostics of the battery inverter, only read if it exposes them; the cooling
+    // state is 0 off, 1 running and 2 derating
+    RegisterGroup {
+        name: "inverter",
+        component: "batteryInverter0",
+        metrics: &[
+            gauge("fems_inverter_temperature_celsius", &[("sensor", "heatsink")], 2, F32),
+            gauge("fems_inverter_temperature_celsius", &[("sensor", "dc_dc")], 4, F32),
+            gauge("fems_inverter_temperature_celsius", &[("sensor", "ambient")], 6, F32),
+            gauge("fems_inverter_dc_bus_voltage_volts", &[], 8, F32),
+            gauge("fems_inverter_cooling_state", &[], 10, U16),
+        ],
+    },
+];
+
+/// Groups only read from the configured targets that list them in `optional_groups`.
+pub const OPTIONAL_GROUPS: [RegisterGroup; 2] = [
+    // SG-Ready heat pump controller; the state is 1 lock, 2 normal, 3 recommendation, 4 force on
+    RegisterGroup {
+        name: "heatpump",
+        component: "ctrlIoHeatPump0",
+        metrics: &[
+            gauge("fems_heatpump_sgready_state", &[], 2, U16),
+            gauge("fems_heatpump_awaiting_hysteresis", &[], 3, U16),
+            counter("fems_heatpump_sgready_switches_total", &[("state", "lock")], 4, F64),
+            counter("fems_heatpump_sgready_switches_total", &[("state", "normal")], 8, F64),
+            counter("fems_heatpump_sgready_switches_total", &[("state", "recommendation")], 12, F64),
+            counter("fems_heatpump_sgready_switches_total", &[("state", "force_on")], 16, F64),
+        ],
+    },
+    // Peak shaving controller; the excess is the grid power above the limit, 0 while below it.
+    // The state is 0 idle, 1 shaving and 2 recharging the ESS.
+    RegisterGroup {
+        name: "peakshaving",
+        component: "ctrlPeakShaving0",
+        metrics: &[
+            gauge("fems_peakshaving_limit_watts", &[], 2, F32),
+            gauge("fems_peakshaving_recharge_limit_watts", &[], 4, F32),
+            gauge("fems_peakshaving_excess_watts", &[], 6, F32),
+            gauge("fems_peakshaving_state", &[], 8, U16),
+        ],
+    },
+];
+
+/// Metric of the active table, owned so the config can change its labels.
+#[derive(Clone)]
+pub struct MetricDef {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub address: u16,
+    pub modbus_type: ModbusType,
+    pub kind: MetricKind,
+    /// Bit numbers of a [`Bitfield`] and the `code` label they are exported with
+    pub bits: Vec<(u8, String)>,
+}
+
+pub struct Group {
+    pub name: String,
+    pub component: String,
+    pub metrics: Vec<MetricDef>,
+    /// Set if the metrics are read once per battery module, their addresses are then offsets
+    pub modules: Option<ModuleLayout>,
+    /// Devices to read the group from, all if `None`
+    pub devices: Option<Vec<Device>>,
+}
+
+impl Group {
+    /// Whether the group is read from `device`.
+    pub fn applies_to(&self, device: Device) -> bool {
+        self.devices.as_ref().is_none_or(|devices| devices.contains(&device))
+    }
+}
+
+/// Changes the static label set of built-in metrics, e.g. to rename phase labels.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LabelOverride {
+    /// Name of the metric to change, all metrics if missing
+    pub metric: Option<String>,
+    /// Only change label sets that contain all of these label values
+    #[serde(default, rename = "match", deserialize_with = "deserialize_label_map")]
+    matches: BTreeMap<String, String>,
+    /// Label names to rename, applied before `set`
+    #[serde(default, deserialize_with = "deserialize_label_renames")]
+    rename: BTreeMap<String, String>,
+    /// Labels to add or replace
+    #[serde(default, deserialize_with = "deserialize_label_map")]
+    set: BTreeMap<String, String>,
+}
+
+impl LabelOverride {
+    fn apply(&self, metric: &mut MetricDef) {
+        if self.metric.as_ref().is_some_and(|m| *m != metric.name) {
+            return;
+        }
+
+        let matches = self
+            .matches
+            .iter()
+            .all(|(label, value)| metric.labels.iter().any(|(l, v)| l == label && v == value));
+        if !matches {
+            return;
+        }
+
+        for (label, _) in &mut metric.labels {
+            if let Some(renamed) = self.rename.get(label) {
+                *label = renamed.clone();
+            }
+        }
+
+        for (label, value) in &self.set {
+            match metric.labels.iter_mut().find(|(l, _)| l == label) {
+                Some((_, v)) => *v = value.clone(),
+                None => metric.labels.push((label.clone(), value.clone())),
+            }
+        }
+    }
+}
+
+/// Builds the table of metrics to read from the built-in groups, applying the label overrides of
+/// `config` in order.
+///
+/// Optional groups enabled by any target come next, restricted to those targets. Fault registers,
+/// battery modules and IO points from the config are appended as additional groups.
+pub fn metric_table(config: &Config) -> Vec<Group> {
+    let mut table: Vec<Group> = REGISTER_GROUPS
+        .iter()
+        .map(|group| built_in_group(group, config, None))
+        .collect();
+
+    for group in &OPTIONAL_GROUPS {
+        let devices: Vec<Device> = config
+            .targets
+            .iter()
+            .filter(|t| t.optional_groups.iter().any(|g| g == group.name))
+            .map(|t| t.device())
+            .collect();
+        if !devices.is_empty() {
+            table.push(built_in_group(group, config, Some(devices)));
+        }
+    }
+
+    table.extend(custom_groups(&config.targets));
+    table.extend(fault_groups(&config.faults));
+    table.extend(module_groups(&config.batteries));
+    table.extend(io_group(&config.io));
+    table
+}
+
+/// Owned copy of a built-in group, with the label overrides of `config` applied.
+fn built_in_group(group: &RegisterGroup, config: &Config, devices: Option<Vec<Device>>) -> Group {
+    Group {
+        name: group.name.to_string(),
+        component: group.component.to_string(),
+        metrics: group
+            .metrics
+            .iter()
+            .map(|metric| {
+                let mut def = MetricDef {
+                    name: metric.name.to_string(),
+                    labels: metric
+                        .labels
+                        .iter()
+                        .map(|(l, v)| (l.to_string(), v.to_string()))
+                        .collect(),
+                    address: metric.address,
+                    modbus_type: metric.modbus_type,
+                    kind: metric.kind,
+                    bits: Vec::new(),
+                };
+
+                for label_override in &config.labels {
+                    label_override.apply(&mut def);
+                }
+
+                def
+            })
+            .collect(),
+        modules: None,
+        devices,
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{
+    modbus::Series,
+    registers::{check_metric_name, deserialize_label_map, deserialize_label_renames},
+};
+
+/// Rewrites metric names and labels, so dashboards built for other exporters keep working.
+///
+/// A rule applies to every series whose name matches `metric` and whose labels match all
+/// regexes in `labels`. Rules are applied in order, each one sees the result of the previous.
+#[derive(Deserialize)]
+#[serde(try_from = "RawRule")]
+pub struct RelabelRule {
+    metric: Regex,
+    labels: Vec<(String, Regex)>,
+    name: Option<String>,
+    rename_labels: BTreeMap<String, String>,
+    set_labels: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRule {
+    /// Regex matched against the whole metric name
+    #[serde(default = "match_all")]
+    metric: String,
+    /// Regexes matched against whole label values, series without the label never match
+    #[serde(default, deserialize_with = "deserialize_label_map")]
+    labels: BTreeMap<String, String>,
+    /// New metric name, may reference capture groups of `metric` like `${1}`
+    name: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_label_renames")]
+    rename_labels: BTreeMap<String, String>,
+    #[serde(default, deserialize_with = "deserialize_label_map")]
+    set_labels: BTreeMap<String, String>,
+}
+
+fn match_all() -> String {
+    ".*".to_string()
+}
+
+fn anchored(regex: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{regex})$"))
+}
+
+impl TryFrom<RawRule> for RelabelRule {
+    type Error = String;
+
+    fn try_from(raw: RawRule) -> Result<Self, Self::Error> {
+        for (from, to) in &raw.rename_labels {
+            if let Some((other, _)) = raw.rename_labels.iter().find(|(other, t)| *other < from && *t == to) {
+                return Err(format!("labels {other:?} and {from:?} are both renamed to {to:?}"));
+            }
+            // Every series has a fems_id and matching ones have the labels the rule matches on,
+            // unless the rule renames them as well
+            let existing = to == "fems_id" || raw.labels.contains_key(to);
+            if existing && !raw.rename_labels.contains_key(to) {
+                return Err(format!("label {from:?} is renamed to the existing label {to:?}"));
+            }
+        }
+
+        let labels = raw
+            .labels
+            .into_iter()
+            .map(|(label, regex)| Ok((label, anchored(&regex)?)))
+            .collect::<Result<_, regex::Error>>()
+            .map_err(|e| e.to_string())?;
+        // Names referencing capture groups are checked with the metrics they are applied to
+        if let Some(name) = raw.name.as_deref().filter(|name| !name.contains('$')) {
+            check_metric_name(name)?;
+        }
+
+        Ok(RelabelRule {
+            metric: anchored(&raw.metric).map_err(|e| e.to_string())?,
+            labels,
+            name: raw.name,
+            rename_labels: raw.rename_labels,
+            set_labels: raw.set_labels,
+        })
+    }
+}
+
+impl RelabelRule {
+    fn matches(&self, series: &Series) -> bool {
+        self.metric.is_match(&series.name)
+            && self.labels.iter().all(|(label, regex)| {
+                series
+                    .labels
+                    .iter()
+                    .any(|(l, v)| l == label && regex.is_match(v))
+            })
+    }
+
+    fn apply(&self, series: &mut Series) {
+        if let Some(name) = &self.name {
+            series.name = self.metric.replace(&series.name, name.as_str()).into_owned();
+        }
+
+        for (label, _) in &mut series.labels {
+            if let Some(renamed) = self.rename_labels.get(label) {
+                *label = renamed.clone();
+            }
+        }
+
+        for (label, value) in &self.set_labels {
+            match series.labels.iter_mut().find(|(l, _)| l == label) {
+                Some((_, v)) => *v = value.clone(),
+                None => series.labels.push((label.clone(), value.clone())),
+            }
+        }
+    }
+}
+
+pub fn relabel(rules: &[RelabelRule], series: &mut [Series]) {
+    for rule in rules {
+        for series in series.iter_mut().filter(|s| rule.matches(s)) {
+            rule.apply(series);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbus::Value;
+
+    fn rules(yaml: &str) -> Vec<RelabelRule> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn series(name: &str, labels: &[(&str, &str)]) -> Series {
+        Series {
+            name: name.to_string(),
+            labels: labels.iter().map(|(l, v)| (l.to_string(), v.to_string())).collect(),
+            value: Value::U16(0),
+        }
+    }
+
+    #[test]
+    fn names_are_rewritten_with_capture_groups() {
+        let rules = rules("[{metric: 'fems_(.*)_watts', name: 'openems_${1}_power'}]");
+        let mut series = [series("fems_ess_power_watts", &[]), series("fems_ess_soc_percent", &[])];
+
+        relabel(&rules, &mut series);
+        assert_eq!(series[0].name, "openems_ess_power_power");
+        assert_eq!(series[1].name, "fems_ess_soc_percent");
+    }
+
+    #[test]
+    fn metric_and_labels_match_whole_values() {
+        let rules = rules("[{metric: fems_grid, labels: {phase: 'l[12]'}, set_labels: {matched: 'yes'}}]");
+        let mut series = [
+            series("fems_grid", &[("phase", "l1")]),
+            series("fems_grid", &[("phase", "l13")]),
+            series("fems_grid_total", &[("phase", "l2")]),
+            series("fems_grid", &[]),
+        ];
+
+        relabel(&rules, &mut series);
+        let matched: Vec<bool> = series.iter().map(|s| s.labels.iter().any(|(l, _)| l == "matched")).collect();
+        assert_eq!(matched, [true, false, false, false]);
+    }
+
+    #[test]
+    fn rules_apply_in_order() {
+        let rules = rules(
+            "[{metric: fems_a, name: fems_b, rename_labels: {fems_id: site}},
+              {metric: fems_b, set_labels: {site: renamed, extra: x}}]",
+        );
+        let mut series = [series("fems_a", &[("fems_id", "home")])];
+
+        relabel(&rules, &mut series);
+        assert_eq!(series[0].name, "fems_b");
+        assert_eq!(
+            series[0].labels,
+            [("site".to_string(), "renamed".to_string()), ("extra".to_string(), "x".to_string())]
+        );
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        assert!(serde_yaml::from_str::<Vec<RelabelRule>>("[{metric: '('}]").is_err());
+        assert!(serde_yaml::from_str::<Vec<RelabelRule>>("[{name: 'fems-state'}]").is_err());
+        assert!(serde_yaml::from_str::<Vec<RelabelRule>>("[{set_labels: {__name__: x}}]").is_err());
+    }
+
+    #[test]
+    fn renames_onto_existing_labels_are_rejected() {
+        let error = |yaml| serde_yaml::from_str::<Vec<RelabelRule>>(yaml).err().map(|e| e.to_string());
+
+        assert!(error("[{rename_labels: {phase: fems_id}}]").unwrap().contains("existing label \"fems_id\""));
+        assert!(error("[{labels: {code: '.*'}, rename_labels: {phase: code}}]").is_some());
+        assert!(error("[{rename_labels: {phase: line, tower: line}}]").unwrap().contains("both renamed"));
+        // A label renamed away makes room for another one
+        assert!(error("[{rename_labels: {fems_id: site, phase: fems_id}}]").is_none());
+    }
+}
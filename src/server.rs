@@ -0,0 +1,74 @@
+//! HTTP server for the listening sockets, speaking HTTP/1.1 and HTTP/2 on the same port.
+
+use std::{future::Future, io, net::TcpListener, time::Duration};
+
+use axum::Router;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::{conn::auto::Builder, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use tracing::{debug, warn};
+
+/// Timeouts applied to every connection, so idle or slow clients don't keep connections open.
+#[derive(Clone, Copy)]
+pub struct Timeouts {
+    /// Time a client may take to send the headers of an HTTP/1 request
+    pub header_read: Duration,
+    /// Time an HTTP/2 client may take to answer a keep-alive ping before it's disconnected
+    pub keep_alive: Duration,
+}
+
+/// Serves `app` on `listener` until `shutdown` completes, then waits for open requests to finish.
+///
+/// Clients choose the protocol, HTTP/2 is used with prior knowledge, e.g. by Prometheus with
+/// `enable_http2`. Upgraded connections like those of /stream are not waited for.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    timeouts: Timeouts,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(timeouts.header_read);
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .keep_alive_interval(timeouts.keep_alive)
+        .keep_alive_timeout(timeouts.keep_alive);
+
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    // E.g. too many open files, the listener itself stays usable
+                    warn!("unable to accept connection: {e}");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let service = TowerToHyperService::new(app.clone());
+        let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+        let connection = graceful.watch(connection.into_owned());
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("connection closed: {e}");
+            }
+        });
+    }
+
+    graceful.shutdown().await;
+    Ok(())
+}
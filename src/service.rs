@@ -0,0 +1,226 @@
+//! Running as a Windows service, for the sites where the exporter runs on a Windows box.
+//!
+//! `fems_exporter -c C:\fems\config.yml service install` installs the service `fems_exporter`,
+//! started at boot with the options given before `service`. Paths have to be absolute, services
+//! start in the system directory. Stopping the service, or shutting Windows down, starts the same
+//! graceful shutdown as SIGTERM does on unix.
+//!
+//! The log goes to the Application event log with `fems_exporter` as source. The source isn't
+//! registered with a message file, so the event viewer prefixes each message with a note that the
+//! description is missing; the message itself is complete.
+
+use std::{env, ffi::OsString, io, process::ExitCode, ptr, sync::Arc, time::Duration};
+
+use clap::{Parser, Subcommand};
+use tracing::{error, Level, Metadata};
+use tracing_subscriber::fmt::writer::MakeWriter;
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode, ServiceInfo,
+        ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+use windows_sys::Win32::{
+    Foundation::HANDLE,
+    System::EventLog::{
+        RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE,
+        REPORT_EVENT_TYPE,
+    },
+};
+
+use crate::{exit_code, lifecycle::{self, Shutdown}, run, runtime, Args};
+
+/// Name of the service and source of its events
+const SERVICE_NAME: &str = "fems_exporter";
+
+#[derive(Subcommand, Debug)]
+pub enum Action {
+    /// Run as the service, only works when started by the service manager
+    Run,
+    /// Install the service, started at boot with the options given before `service`
+    Install,
+    /// Remove the installed service, it has to be stopped before
+    Uninstall,
+}
+
+pub fn main(action: &Action) -> ExitCode {
+    let result = match action {
+        Action::Run => service_dispatcher::start(SERVICE_NAME, ffi_service_main).map_err(|e| {
+            format!("unable to connect to the service manager, `service run` is only started by it: {e}")
+        }),
+        Action::Install => install().map_err(|e| format!("unable to install the service: {e}")),
+        Action::Uninstall => uninstall().map_err(|e| format!("unable to remove the service: {e}")),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn install() -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CREATE_SERVICE)?;
+
+    // Everything before `service install`, so the service runs with the options it was installed with
+    let mut launch_arguments: Vec<OsString> = env::args_os().skip(1).take_while(|arg| arg != "service").collect();
+    launch_arguments.extend(["service".into(), "run".into()]);
+
+    let info = ServiceInfo {
+        name: SERVICE_NAME.into(),
+        display_name: "FEMS exporter".into(),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: env::current_exe().map_err(windows_service::Error::Winapi)?,
+        launch_arguments,
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG)?;
+    service.set_description("Exports metrics of FEMS and OpenEMS devices to Prometheus")?;
+
+    println!("installed service {SERVICE_NAME}");
+    Ok(())
+}
+
+fn uninstall() -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    manager.open_service(SERVICE_NAME, ServiceAccess::DELETE)?.delete()?;
+
+    println!("removed service {SERVICE_NAME}");
+    Ok(())
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Runs the exporter on a thread of the service manager until the service is stopped.
+fn service_main(_arguments: Vec<OsString>) {
+    match EventLog::open() {
+        Some(log) => tracing_subscriber::fmt().with_writer(log).with_ansi(false).without_time().init(),
+        None => tracing_subscriber::fmt::init(),
+    }
+    // Without reporting it as stopped, so the service manager applies the recovery actions
+    lifecycle::exit_on_panic();
+
+    let shutdown = Arc::new(Shutdown::default());
+    let handler_shutdown = shutdown.clone();
+    let handler = move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            handler_shutdown.request();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let status = match service_control_handler::register(SERVICE_NAME, handler) {
+        Ok(status) => status,
+        Err(e) => {
+            error!("unable to register with the service manager: {e}");
+            return;
+        }
+    };
+
+    report(&status, ServiceState::Running, 0);
+    // The options the service was installed with, `service_main` only gets those of `sc start`
+    let code = exit_code(runtime().block_on(run(Args::parse(), shutdown)));
+    report(&status, ServiceState::Stopped, code);
+}
+
+fn report(status: &ServiceStatusHandle, state: ServiceState, code: u8) {
+    let result = status.set_service_status(ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted: match state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        },
+        exit_code: match code {
+            0 => ServiceExitCode::Win32(0),
+            code => ServiceExitCode::ServiceSpecific(code.into()),
+        },
+        checkpoint: 0,
+        wait_hint: Duration::ZERO,
+        process_id: None,
+    });
+    if let Err(e) = result {
+        error!("unable to report the service as {state:?}: {e}");
+    }
+}
+
+/// Event source in the Application log, each formatted log line is written as one event.
+struct EventLog(HANDLE);
+
+impl EventLog {
+    fn open() -> Option<Self> {
+        let source = wide(SERVICE_NAME);
+        // SAFETY: `source` is a NUL terminated UTF-16 string outliving the call
+        let handle = unsafe { RegisterEventSourceW(ptr::null(), source.as_ptr()) };
+        (handle != 0).then_some(EventLog(handle))
+    }
+}
+
+impl<'a> MakeWriter<'a> for EventLog {
+    type Writer = Event<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Event { log: self, kind: EVENTLOG_INFORMATION_TYPE, line: Vec::new() }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        let kind = match *meta.level() {
+            Level::ERROR => EVENTLOG_ERROR_TYPE,
+            Level::WARN => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        Event { log: self, kind, line: Vec::new() }
+    }
+}
+
+/// One log line, reported once it's completely formatted.
+struct Event<'a> {
+    log: &'a EventLog,
+    kind: REPORT_EVENT_TYPE,
+    line: Vec<u8>,
+}
+
+impl io::Write for Event<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Event<'_> {
+    fn drop(&mut self) {
+        let line = String::from_utf8_lossy(&self.line);
+        let line = line.trim_end();
+        if line.is_empty() {
+            return;
+        }
+
+        let message = wide(line);
+        let strings = [message.as_ptr()];
+        // SAFETY: the handle stays registered for the lifetime of the process and `strings` holds one
+        // NUL terminated UTF-16 string outliving the call
+        unsafe {
+            ReportEventW(self.log.0, self.kind, 0, 0, ptr::null_mut(), 1, 0, strings.as_ptr(), ptr::null());
+        }
+    }
+}
+
+/// `s` as NUL terminated UTF-16, as the Windows API takes strings.
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain([0]).collect()
+}
@@ -0,0 +1,239 @@
+//! Synthetic FEMS for demos, dashboards and end-to-end tests, enabled with `--simulate`.
+//!
+//! Values follow the time of day in UTC: PV production peaks at noon, the battery charges from
+//! the surplus until it's full and covers the consumption from the evening on. Register words
+//! are made up and decoded like those read via Modbus, so every metric of the table is exported.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet},
+    f64::consts::PI,
+    hash::{Hash, Hasher},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use futures::future::{BoxFuture, FutureExt};
+
+use crate::{
+    backend::{Backend, BackendKind, Reading},
+    error::ReadError,
+    modbus::{decode_metric, Device, GroupSamples, ModbusState},
+    modules::Count,
+    registers::{Bitfield, Coil, DiscreteInput, MetricDef, MetricKind, F32, F64, U16},
+};
+
+/// Start of the simulated energy counters, 2024-01-01
+const COUNTERS_SINCE: f64 = 1_704_067_200.0;
+
+/// Towers, or modules per tower, of battery groups whose counts are read from the device.
+const MODULES: u16 = 4;
+
+/// Share of the total power on each phase.
+const PHASES: [f64; 3] = [0.36, 0.33, 0.31];
+
+/// Usable battery capacity in Wh.
+const CAPACITY: f64 = 10_000.0;
+
+/// Voltage of each phase in V.
+const VOLTAGE: f64 = 230.0;
+
+/// Highest charge and discharge power in W.
+const MAX_ESS_POWER: f64 = 5_000.0;
+
+/// Power flows of the site at one point in time, signed like OpenEMS does.
+struct Site {
+    production: f64,
+    consumption: f64,
+    /// Positive while discharging
+    ess: f64,
+    /// Positive while buying
+    grid: f64,
+    soc: f64,
+    /// Seconds since [`COUNTERS_SINCE`]
+    elapsed: f64,
+}
+
+impl Site {
+    /// The site of `device` at the Unix time `now`; sites of different devices differ in size.
+    fn at(device: Device, now: f64) -> Self {
+        let mut hasher = DefaultHasher::new();
+        device.hash(&mut hasher);
+        let scale = 0.75 + (hasher.finish() % 50) as f64 / 100.0;
+
+        let hour = now.rem_euclid(86_400.0) / 3_600.0;
+        // Slow wobble, so the curves don't look drawn with a ruler
+        let noise = |period: f64| (now / period).sin();
+
+        let daylight = ((hour - 6.0) / 12.0 * PI).sin().max(0.0);
+        let production = 8_000.0 * scale * daylight * (0.95 + 0.05 * noise(37.0));
+        let consumption = scale
+            * (350.0
+                + 900.0 * (-((hour - 7.5) / 1.0).powi(2)).exp()
+                + 1_500.0 * (-((hour - 19.0) / 1.5).powi(2)).exp()
+                + 100.0 * noise(53.0).abs());
+
+        // Charging from 9:00 until full at 15:00, then discharging down to 15% by 9:00
+        let (soc, ess) = match hour {
+            h if (9.0..15.0).contains(&h) => {
+                let progress = (h - 9.0) / 6.0;
+                let soc = 15.0 + 80.0 * progress * progress * (3.0 - 2.0 * progress);
+                (soc, -(production - consumption).clamp(0.0, MAX_ESS_POWER))
+            }
+            h if (15.0..19.0).contains(&h) => (95.0, 0.0),
+            h => {
+                let since = if h >= 19.0 { h - 19.0 } else { h + 5.0 };
+                let soc = 95.0 - 80.0 * since / 14.0;
+                (soc, (consumption - production).clamp(0.0, MAX_ESS_POWER))
+            }
+        };
+
+        Site {
+            production,
+            consumption,
+            ess,
+            grid: consumption - production - ess,
+            soc,
+            elapsed: (now - COUNTERS_SINCE).max(0.0),
+        }
+    }
+
+    /// Counter growing by `wh_per_day` a day, in Wh.
+    fn energy(&self, wh_per_day: f64) -> f64 {
+        wh_per_day * self.elapsed / 86_400.0
+    }
+
+    /// Value of `metric`, `phase` is the index of its phase if it's one of three.
+    fn value(&self, component: &str, metric: &MetricDef, phase: Option<usize>) -> f64 {
+        let split = |total: f64| phase.map_or(total, |p| total * PHASES[p]);
+
+        match (component, metric.address) {
+            ("_sum", 222) => 0.0,
+            ("_sum", 302) => self.soc.round(),
+            ("_sum", 303 | 415) => self.ess,
+            ("_sum", 391 | 393 | 395) => split(self.ess),
+            ("_sum", 309) => 0.0,
+            ("_sum", 315 | 397 | 399 | 401) => split(self.grid),
+            ("_sum", 327) => self.production,
+            ("_sum", 339) => self.production * 0.7,
+            ("_sum", 403 | 405 | 407) => split(self.production * 0.3),
+            ("_sum", 343 | 409 | 411 | 413) => split(self.consumption),
+            ("_sum", 351 | 383) => self.energy(6_000.0),
+            ("_sum", 355 | 387) => self.energy(5_500.0),
+            ("_sum", 359) => self.energy(4_000.0),
+            ("_sum", 363) => self.energy(12_000.0),
+            ("_sum", 367) => self.energy(25_000.0),
+            ("_sum", 371) => self.energy(7_500.0),
+            ("_sum", 375) => self.energy(17_500.0),
+            ("_sum", 379) => self.energy(17_000.0),
+            ("_sum", 417) => 1.0,
+            ("_sum", 418) => CAPACITY,
+            _ => match metric.name.as_str() {
+                "fems_inverter_temperature_celsius" if metric.labels.iter().any(|(_, v)| v == "ambient") => 22.0,
+                "fems_inverter_temperature_celsius" => 25.0 + 20.0 * self.ess.abs() / MAX_ESS_POWER,
+                "fems_inverter_dc_bus_voltage_volts" => 700.0,
+                "fems_consumption_current_amperes" => self.consumption * PHASES[phase_of(metric)] / VOLTAGE,
+                "fems_consumption_power_factor" => 0.95,
+                _ if metric.kind == MetricKind::Counter => self.energy(100.0),
+                _ => 0.0,
+            },
+        }
+    }
+}
+
+/// Index of the phase in the `phase` label of `metric`, the first one if it has none.
+fn phase_of(metric: &MetricDef) -> usize {
+    match metric.labels.iter().find(|(label, _)| label == "phase").map(|(_, v)| v.as_str()) {
+        Some("l2") => 1,
+        Some("l3") => 2,
+        _ => 0,
+    }
+}
+
+/// Register words encoding `value` as `metric` is read from the device.
+fn encode(metric: &MetricDef, value: f64) -> Vec<u16> {
+    let words = |bytes: &[u8]| bytes.chunks(2).map(|w| u16::from_be_bytes([w[0], w[1]])).collect();
+
+    match metric.modbus_type {
+        U16 => vec![value as u16],
+        F32 => words(&(value as f32).to_be_bytes()),
+        F64 => words(&value.to_be_bytes()),
+        // Relays are off and there are no faults
+        Coil | DiscreteInput | Bitfield => vec![0],
+    }
+}
+
+/// Serves synthetic values instead of reading a device.
+pub struct Simulated;
+
+impl Simulated {
+    fn read(&self, state: &ModbusState, device: Device, due: Option<&HashSet<String>>) -> Reading {
+        let started_at = Instant::now();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        let site = Site::at(device, now);
+
+        let mut groups: Vec<GroupSamples> = Vec::new();
+        for group in state.table().iter() {
+            if due.is_some_and(|due| !due.contains(&group.name)) || !group.applies_to(device) {
+                continue;
+            }
+
+            let positions: Vec<Vec<(String, String)>> = match &group.modules {
+                None => vec![Vec::new()],
+                Some(layout) => {
+                    let count = |count| match count {
+                        Count::Fixed(count) => count,
+                        Count::Register { .. } => MODULES,
+                    };
+                    let modules = count(layout.modules);
+                    (0..count(layout.towers))
+                        .flat_map(|tower| {
+                            (0..modules).map(move |module| {
+                                vec![("tower".to_string(), tower.to_string()), ("module".to_string(), module.to_string())]
+                            })
+                        })
+                        .collect()
+                }
+            };
+
+            let mut samples = Vec::new();
+            for extra in &positions {
+                for metric in &group.metrics {
+                    let phase = match metric.address {
+                        391 | 397 | 403 | 409 => Some(0),
+                        393 | 399 | 405 | 411 => Some(1),
+                        395 | 401 | 407 | 413 => Some(2),
+                        _ => None,
+                    }
+                    .filter(|_| group.component == "_sum");
+                    let words = encode(metric, site.value(&group.component, metric, phase));
+                    decode_metric(metric, &words, extra, &mut samples);
+                }
+            }
+            groups.push((group.name.clone(), samples));
+        }
+
+        Reading { groups, registers_read: 0, duration: started_at.elapsed() }
+    }
+}
+
+impl Backend for Simulated {
+    fn kind(&self) -> BackendKind {
+        BackendKind::Simulated
+    }
+
+    fn connect<'a>(&'a self, _state: &'a ModbusState, _device: Device) -> BoxFuture<'a, Result<(), ReadError>> {
+        async { Ok(()) }.boxed()
+    }
+
+    fn read_metrics<'a>(
+        &'a self,
+        state: &'a ModbusState,
+        device: Device,
+        due: Option<&'a HashSet<String>>,
+    ) -> BoxFuture<'a, Result<Reading, ReadError>> {
+        let reading = self.read(state, device, due);
+        async { Ok(reading) }.boxed()
+    }
+}
@@ -0,0 +1,135 @@
+use std::{collections::BTreeMap, net::SocketAddr, time::Duration};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::{
+    error::ReadError,
+    modbus::{default_unit_id, deserialize_host, read_samples, Device, ModbusState, Series},
+};
+
+#[derive(Deserialize)]
+pub struct StreamParams {
+    #[serde(deserialize_with = "deserialize_host")]
+    host: SocketAddr,
+    #[serde(default = "default_unit_id")]
+    unit_id: u8,
+    /// Defaults to the `fems_id` of the configured target, see [`ModbusState::default_fems_id`]
+    fems_id: Option<String>,
+    /// Seconds between two updates, defaults to `--stream-interval`
+    interval: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Update<'a> {
+    Metrics(Vec<Metric<'a>>),
+    Error {
+        #[serde(flatten)]
+        error: &'a ReadError,
+        description: String,
+    },
+}
+
+#[derive(Serialize)]
+struct Metric<'a> {
+    name: &'a str,
+    labels: BTreeMap<&'a str, &'a str>,
+    value: f64,
+}
+
+impl<'a> From<&'a Series> for Metric<'a> {
+    fn from(series: &'a Series) -> Self {
+        Metric {
+            name: &series.name,
+            labels: series
+                .labels
+                .iter()
+                .map(|(l, v)| (l.as_str(), v.as_str()))
+                .collect(),
+            value: series.value.as_f64(),
+        }
+    }
+}
+
+pub async fn stream(
+    ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    Query(params): Query<StreamParams>,
+    State(state): State<ModbusState>,
+) -> Response {
+    let device = Device {
+        host: params.host,
+        unit_id: params.unit_id,
+    };
+    let fems_id = params.fems_id.clone().unwrap_or_else(|| state.default_fems_id(device));
+    let scope = state.tenants.authorize(&headers);
+    if let Err(denied) = scope.and_then(|scope| state.tenants.check(&scope, device, &fems_id)) {
+        return denied.into_response();
+    }
+
+    ws.on_upgrade(move |socket| push_updates(socket, params, fems_id, state))
+}
+
+async fn push_updates(mut socket: WebSocket, params: StreamParams, fems_id: String, state: ModbusState) {
+    let seconds = params.interval.unwrap_or(state.stream_interval).max(1);
+    let mut ticker = interval(Duration::from_secs(seconds));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {},
+            message = socket.recv() => match message {
+                // Clients are not expected to send anything, so only watch for the connection closing
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => continue,
+            },
+        }
+
+        let device = Device {
+            host: params.host,
+            unit_id: params.unit_id,
+        };
+        let series = read_samples(&state, device)
+            .await
+            .map(|samples| state.series(&samples, &fems_id));
+        let update = match &series {
+            Ok(series) => Update::Metrics(series.iter().map(Metric::from).collect()),
+            Err(error) => Update::Error {
+                error,
+                description: error.to_string(),
+            },
+        };
+
+        let text = serde_json::to_string(&update).expect("updates are always serializable");
+        if socket.send(Message::Text(text)).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_updates_have_one_message() {
+        let error = ReadError::Connect { host: "127.0.0.1:502".parse().unwrap(), message: "connection refused".to_string() };
+        let update = Update::Error { error: &error, description: error.to_string() };
+
+        let json = serde_json::to_string(&update).unwrap();
+        assert_eq!(json.matches("\"message\"").count(), 1);
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(json["error"]["kind"], "connect");
+        assert_eq!(json["error"]["message"], "connection refused");
+        assert_eq!(json["error"]["description"], error.to_string());
+    }
+}
@@ -0,0 +1,110 @@
+use std::fmt::Write;
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use crate::{
+    modbus::{Device, ModbusState, TargetStatus},
+    tenants::Scope,
+};
+
+#[derive(Serialize)]
+pub struct TargetInfo {
+    #[serde(flatten)]
+    device: Device,
+    #[serde(flatten)]
+    status: TargetStatus,
+}
+
+/// Every known device `scope` may see with its status, sorted by address.
+pub fn target_infos(state: &ModbusState, scope: &Scope) -> Vec<TargetInfo> {
+    let mut targets: Vec<TargetInfo> = state
+        .targets
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(device, _)| state.tenants.owns(scope, **device))
+        .map(|(device, status)| TargetInfo {
+            device: *device,
+            status: status.clone(),
+        })
+        .collect();
+    targets.sort_by_key(|t| (t.device.host, t.device.unit_id));
+    targets
+}
+
+/// Lists every known device with its connection state and discovered register support.
+///
+/// Browsers get an HTML table, everything else JSON.
+pub async fn targets(headers: HeaderMap, State(state): State<ModbusState>) -> Response {
+    let scope = match state.tenants.authorize(&headers) {
+        Ok(scope) => scope,
+        Err(denied) => return denied.into_response(),
+    };
+    let targets = target_infos(&state, &scope);
+
+    let wants_html = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+
+    if wants_html {
+        Html(render_html(&targets)).into_response()
+    } else {
+        Json(targets).into_response()
+    }
+}
+
+fn render_html(targets: &[TargetInfo]) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html>\n<head><title>FEMS targets</title></head>\n<body>\n<h1>Targets</h1>\n<table border=\"1\">\n\
+         <tr><th>Host</th><th>Unit</th><th>Connected</th><th>Last scrape</th><th>Failures</th><th>Last error</th><th>Recent errors</th><th>Unsupported groups</th></tr>\n",
+    );
+
+    for TargetInfo { device, status } in targets {
+        let last_scrape = status
+            .last_scrape
+            .map(|t| t.to_string())
+            .unwrap_or_else(|| "never".to_string());
+        let unsupported: Vec<&str> = status
+            .register_groups
+            .iter()
+            .filter(|(_, support)| !support.supported)
+            .map(|(group, _)| group.as_str())
+            .collect();
+        let recent_errors: Vec<String> = status
+            .recent_errors
+            .iter()
+            .rev()
+            .map(|record| escape(&format!("{}: {}", record.timestamp, record.description)))
+            .collect();
+
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            device.host,
+            device.unit_id,
+            if status.connected { "yes" } else { "no" },
+            last_scrape,
+            status.consecutive_failures,
+            escape(&status.last_error.as_ref().map(|e| e.to_string()).unwrap_or_default()),
+            recent_errors.join("<br>"),
+            unsupported.join(", "),
+        );
+    }
+
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
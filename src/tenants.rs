@@ -0,0 +1,206 @@
+//! Tenants, for one exporter serving the targets of several customers.
+//!
+//! Once tenants are configured, every request needs a `Authorization: Bearer <token>` header.
+//! A tenant only gets to read its own configured targets, under their configured fems_id. Admin
+//! tenants can read everything, including devices that aren't configured, and use the endpoints
+//! that affect all targets.
+
+use std::collections::HashMap;
+
+use axum::{
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+
+use crate::{config::Target, credentials::Secret, modbus::Device};
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantConfig {
+    pub name: String,
+    /// Bearer token identifying the tenant, usually `${VAR}` or `{file: ...}`
+    pub token: Secret,
+    /// Whether the tenant can read every target and reload or control the exporter
+    #[serde(default)]
+    pub admin: bool,
+}
+
+/// What a request may access.
+pub enum Scope<'a> {
+    All,
+    Tenant(&'a str),
+}
+
+/// Why a request was refused.
+#[derive(Clone, Copy)]
+pub enum Denied {
+    /// No token or an unknown one
+    Unauthorized,
+    /// A valid token of a tenant that doesn't own what was asked for
+    Forbidden,
+}
+
+impl Denied {
+    pub fn status(self) -> StatusCode {
+        match self {
+            Denied::Unauthorized => StatusCode::UNAUTHORIZED,
+            Denied::Forbidden => StatusCode::FORBIDDEN,
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            Denied::Unauthorized => "a valid tenant token is required",
+            Denied::Forbidden => "not permitted for this tenant",
+        }
+    }
+}
+
+impl IntoResponse for Denied {
+    fn into_response(self) -> Response {
+        let mut response = (self.status(), self.message()).into_response();
+        if let Denied::Unauthorized = self {
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+        }
+        response
+    }
+}
+
+/// Configured tenants and the targets they own, empty if the exporter isn't shared.
+#[derive(Default)]
+pub struct Tenants {
+    tenants: Vec<TenantConfig>,
+    /// Owning tenant and fems_id of every configured target with a tenant
+    owners: HashMap<Device, (String, String)>,
+}
+
+impl Tenants {
+    pub fn new(tenants: &[TenantConfig], targets: &[Target]) -> Self {
+        let owners = targets
+            .iter()
+            .filter_map(|t| Some((t.device(), (t.tenant.clone()?, t.fems_id.clone()))))
+            .collect();
+
+        Tenants {
+            tenants: tenants.to_vec(),
+            owners,
+        }
+    }
+
+    /// Identifies the tenant by the bearer token of the request, everything is allowed without tenants.
+    pub fn authorize(&self, headers: &HeaderMap) -> Result<Scope<'_>, Denied> {
+        if self.tenants.is_empty() {
+            return Ok(Scope::All);
+        }
+
+        let token = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|a| a.to_str().ok())
+            .and_then(|a| a.strip_prefix("Bearer "))
+            .ok_or(Denied::Unauthorized)?;
+
+        let tenant = self
+            .tenants
+            .iter()
+            .find(|t| constant_time_eq(t.token.expose().as_bytes(), token.as_bytes()))
+            .ok_or(Denied::Unauthorized)?;
+
+        Ok(match tenant.admin {
+            true => Scope::All,
+            false => Scope::Tenant(&tenant.name),
+        })
+    }
+
+    /// Whether `scope` may see `device` at all.
+    pub fn owns(&self, scope: &Scope, device: Device) -> bool {
+        match scope {
+            Scope::All => true,
+            Scope::Tenant(tenant) => self.owners.get(&device).is_some_and(|(owner, _)| owner == tenant),
+        }
+    }
+
+    /// Checks that `scope` may read `device` as `fems_id`.
+    pub fn check(&self, scope: &Scope, device: Device, fems_id: &str) -> Result<(), Denied> {
+        match scope {
+            Scope::All => Ok(()),
+            Scope::Tenant(tenant) => match self.owners.get(&device) {
+                Some((owner, id)) if owner == tenant && id == fems_id => Ok(()),
+                _ => Err(Denied::Forbidden),
+            },
+        }
+    }
+
+    /// Whether any tenant is an admin, without one nobody can be authenticated as admin.
+    pub fn has_admin(&self) -> bool {
+        self.tenants.iter().any(|t| t.admin)
+    }
+
+    /// Checks that `scope` may use endpoints that affect every target.
+    pub fn check_admin(&self, scope: &Scope) -> Result<(), Denied> {
+        match scope {
+            Scope::All => Ok(()),
+            Scope::Tenant(_) => Err(Denied::Forbidden),
+        }
+    }
+}
+
+/// Compares without returning early, so response times don't reveal how much of a token matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tenants() -> Tenants {
+        let tenants: Vec<TenantConfig> =
+            serde_yaml::from_str("[{name: ops, token: secret, admin: true}, {name: acme, token: acme}]").unwrap();
+        let targets: Vec<Target> = serde_yaml::from_str(
+            "[{host: '10.0.0.1', fems_id: home, tenant: acme}, {host: '10.0.0.2', fems_id: other}]",
+        )
+        .unwrap();
+        Tenants::new(&tenants, &targets)
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, format!("Bearer {token}").parse().unwrap());
+        headers
+    }
+
+    fn device(host: &str) -> Device {
+        Device { host: format!("{host}:502").parse().unwrap(), unit_id: 1 }
+    }
+
+    #[test]
+    fn tokens_identify_the_tenant() {
+        let tenants = tenants();
+
+        assert!(matches!(tenants.authorize(&bearer("secret")), Ok(Scope::All)));
+        assert!(matches!(tenants.authorize(&bearer("acme")), Ok(Scope::Tenant("acme"))));
+        assert!(matches!(tenants.authorize(&bearer("acm")), Err(Denied::Unauthorized)));
+        assert!(matches!(tenants.authorize(&HeaderMap::new()), Err(Denied::Unauthorized)));
+        // Without tenants the exporter isn't shared
+        assert!(matches!(Tenants::default().authorize(&HeaderMap::new()), Ok(Scope::All)));
+    }
+
+    #[test]
+    fn tenants_only_read_their_targets_as_configured() {
+        let tenants = tenants();
+        let acme = Scope::Tenant("acme");
+
+        assert!(tenants.owns(&acme, device("10.0.0.1")));
+        assert!(!tenants.owns(&acme, device("10.0.0.2")));
+        assert!(tenants.check(&acme, device("10.0.0.1"), "home").is_ok());
+        assert!(matches!(tenants.check(&acme, device("10.0.0.1"), "spoofed"), Err(Denied::Forbidden)));
+        assert!(matches!(tenants.check(&acme, device("10.0.0.3"), "home"), Err(Denied::Forbidden)));
+
+        assert!(tenants.check(&Scope::All, device("10.0.0.3"), "anything").is_ok());
+        assert!(tenants.check_admin(&Scope::All).is_ok());
+        assert!(tenants.check_admin(&acme).is_err());
+    }
+}